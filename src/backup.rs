@@ -0,0 +1,251 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, ErrorKind, Read},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    database::{self, ExecutorConnection},
+    storage::Images,
+    tar,
+};
+
+const DATABASE: &str = "zhaba.db3";
+const IMAGES: &str = "images/";
+const CHECKSUMS: &str = "SHA256SUMS";
+
+/// What a restored backup contained.
+pub struct Restored {
+    pub images: usize,
+    /// Images posts refer to that were already missing when the backup was made.
+    pub missing: usize,
+}
+
+/// Bundles a snapshot of the database and the images its posts refer to into a
+/// tarball at `out`, returning how many images went in.
+pub async fn create(db: &ExecutorConnection, images: &Images, out: &Path) -> Result<usize> {
+    let snapshot = with_suffix(out, ".db3.tmp");
+    let _ = fs::remove_file(&snapshot);
+    db.backup(snapshot.clone()).await?;
+    let result = tokio::task::spawn_blocking({
+        let (snapshot, images, out) = (snapshot.clone(), images.clone(), out.to_owned());
+        move || write_archive(&snapshot, &images, &out)
+    })
+    .await?;
+    let _ = fs::remove_file(&snapshot);
+    result
+}
+
+fn write_archive(snapshot: &Path, images: &Images, out: &Path) -> Result<usize> {
+    let referenced = database::check_snapshot(snapshot)?;
+    let tmp = with_suffix(out, ".tmp");
+    let result = (|| {
+        let mut builder = tar::Builder::new(BufWriter::new(File::create(&tmp)?));
+        let mut sums = String::new();
+        let file = File::open(snapshot)?;
+        let size = file.metadata()?.len();
+        append(&mut builder, &mut sums, DATABASE, size, now(), file)?;
+        let mut count = 0;
+        for image in &referenced {
+            let Some((size, data)) = images.open_blocking(image)? else {
+                tracing::warn!("Leaving the missing image {image} out of the backup");
+                continue;
+            };
+            append(
+                &mut builder,
+                &mut sums,
+                &format!("{IMAGES}{image}"),
+                size,
+                now(),
+                data,
+            )?;
+            count += 1;
+        }
+        builder.append(CHECKSUMS, sums.len() as u64, now(), sums.as_bytes())?;
+        let file = builder.finish()?.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, out)?;
+        Ok(count)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn append(
+    builder: &mut tar::Builder<BufWriter<File>>,
+    sums: &mut String,
+    name: &str,
+    size: u64,
+    mtime: u64,
+    data: impl Read,
+) -> io::Result<()> {
+    let mut hashing = Hashing::new(data);
+    builder.append(name, size, mtime, &mut hashing)?;
+    sums.push_str(&format!("{:x}  {name}\n", hashing.hasher.finalize()));
+    Ok(())
+}
+
+/// Replaces the database at `db_path` with a backup made by `create`, adding its
+/// images to the storage. Nothing is replaced unless the whole backup checks out.
+/// The server must not be running.
+pub async fn restore(archive: &Path, db_path: &Path, images: &Images) -> Result<Restored> {
+    let staged_db = with_suffix(db_path, ".restore");
+    let staged_images = with_suffix(db_path, ".restore-images");
+    let result = async {
+        let (restored, missing) = tokio::task::spawn_blocking({
+            let (archive, staged_db, staged_images) =
+                (archive.to_owned(), staged_db.clone(), staged_images.clone());
+            move || extract(&archive, &staged_db, &staged_images)
+        })
+        .await??;
+        for image in &restored {
+            let data = tokio::fs::read(staged_images.join(image)).await?;
+            images.put(image, data.into()).await?;
+        }
+        // the old database's log would be replayed over the restored one
+        for suffix in ["-wal", "-shm"] {
+            match fs::remove_file(with_suffix(db_path, suffix)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        fs::rename(&staged_db, db_path)?;
+        Ok(Restored {
+            images: restored.len(),
+            missing,
+        })
+    }
+    .await;
+    let _ = fs::remove_file(&staged_db);
+    let _ = fs::remove_dir_all(&staged_images);
+    result
+}
+
+/// Unpacks a backup next to where it goes, checking it along the way. Returns the
+/// images and how many images the database refers to that aren't there.
+fn extract(archive: &Path, staged_db: &Path, staged_images: &Path) -> Result<(Vec<String>, usize)> {
+    let _ = fs::remove_dir_all(staged_images);
+    fs::create_dir_all(staged_images)?;
+    let mut archive = tar::Archive::new(BufReader::new(File::open(archive)?));
+    let mut hashes = HashMap::new();
+    let mut checksums = None;
+    while let Some(mut entry) = archive.next_entry()? {
+        let name = entry.name.clone();
+        if name == CHECKSUMS {
+            let mut sums = String::new();
+            entry.read_to_string(&mut sums)?;
+            checksums = Some(sums);
+            continue;
+        }
+        let target = match name.strip_prefix(IMAGES) {
+            _ if name == DATABASE => staged_db.to_owned(),
+            Some(image) if is_plain_name(image) => staged_images.join(image),
+            _ => return Err(eyre!("Unexpected file {name:?} in the backup")),
+        };
+        let mut hashing = Hashing::new(&mut entry);
+        let mut file = File::create(target)?;
+        io::copy(&mut hashing, &mut file)?;
+        file.sync_all()?;
+        hashes.insert(name, format!("{:x}", hashing.hasher.finalize()));
+    }
+
+    let checksums = checksums.ok_or_else(|| eyre!("The backup has no checksums"))?;
+    let mut listed = 0;
+    for line in checksums.lines() {
+        let (sum, name) = line
+            .split_once("  ")
+            .ok_or_else(|| eyre!("Invalid checksum line {line:?}"))?;
+        match hashes.get(name) {
+            Some(hash) if hash == sum => listed += 1,
+            Some(_) => return Err(eyre!("{name} in the backup is corrupted")),
+            None => return Err(eyre!("{name} is missing from the backup")),
+        }
+    }
+    if listed != hashes.len() {
+        return Err(eyre!("The backup has files without a checksum"));
+    }
+    if !hashes.contains_key(DATABASE) {
+        return Err(eyre!("The backup has no database"));
+    }
+
+    let referenced = database::check_snapshot(staged_db)?;
+    let missing = referenced
+        .iter()
+        .filter(|image| !hashes.contains_key(&format!("{IMAGES}{image}")))
+        .count();
+    let images = hashes
+        .into_keys()
+        .filter_map(|name| name.strip_prefix(IMAGES).map(str::to_string))
+        .collect();
+    Ok((images, missing))
+}
+
+/// Removes all but the newest `keep` backups made by the scheduled job, 0 keeps them all.
+pub fn prune(directory: &Path, keep: usize) -> io::Result<()> {
+    if keep == 0 {
+        return Ok(());
+    }
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let name = entry?.file_name();
+        if let Some(name) = name.to_str() {
+            if name.starts_with("zhaba-") && name.ends_with(".tar") {
+                backups.push(name.to_string());
+            }
+        }
+    }
+    // the names hold the time they were made, so they sort oldest first
+    backups.sort_unstable();
+    for name in &backups[..backups.len().saturating_sub(keep)] {
+        fs::remove_file(directory.join(name))?;
+        tracing::info!("Removed old backup {name}");
+    }
+    Ok(())
+}
+
+/// Whether an archived image can be stored under its name as is.
+pub fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\'])
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+struct Hashing<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R> Hashing<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, RngCore};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{config::AntiSpam, templates::models::ChallengeMode};
+
+// past this many tracked entries, expired ones are pruned
+const PRUNE_THRESHOLD: usize = 4096;
+const MAX_RESPONSE_LENGTH: usize = 32;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeKind {
+    /// A hashcash-style puzzle solved by `static/pow.js`.
+    #[default]
+    #[serde(rename = "pow")]
+    ProofOfWork,
+    /// A small sum the poster answers by hand, works without JavaScript.
+    Arithmetic,
+}
+
+impl ChallengeKind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::ProofOfWork => "pow",
+            Self::Arithmetic => "arithmetic",
+        }
+    }
+}
+
+/// What the post form shows for a challenge.
+pub enum Prompt {
+    /// Find a response where `sha256("<token>:<response>")` starts with this many zero bits.
+    ProofOfWork(u32),
+    Question(String),
+}
+
+pub struct Challenge {
+    pub token: String,
+    pub prompt: Prompt,
+}
+
+/// Issues and checks anti-spam challenges. Tokens are signed and carry their
+/// own expiry, so nothing is stored until one is answered, at which point it's
+/// remembered until it expires so it can't be answered again.
+pub struct Challenges {
+    cfg: AntiSpam,
+    secret: Vec<u8>,
+    used: Mutex<HashMap<String, u64>>,
+    posts: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl Challenges {
+    pub fn new(cfg: AntiSpam, secret: &[u8]) -> Self {
+        Self {
+            cfg,
+            secret: secret.to_vec(),
+            used: Mutex::default(),
+            posts: Mutex::default(),
+        }
+    }
+
+    /// Whether a poster from `ip` has to solve a challenge on a board with `mode`.
+    pub fn required(&self, mode: ChallengeMode, ip: IpAddr) -> bool {
+        match mode {
+            ChallengeMode::Off => false,
+            ChallengeMode::Auto => self.is_suspicious(ip),
+            ChallengeMode::Always => true,
+        }
+    }
+
+    pub fn issue(&self, now: u64) -> Challenge {
+        let mut nonce = [0u8; 12];
+        thread_rng().fill_bytes(&mut nonce);
+        let expires = now + self.cfg.ttl_secs;
+        let nonce = hex(&nonce);
+        let token = format!("{expires}.{nonce}.{}", hex(&self.sign(expires, &nonce)));
+        let prompt = match self.cfg.kind {
+            ChallengeKind::ProofOfWork => Prompt::ProofOfWork(self.cfg.difficulty),
+            ChallengeKind::Arithmetic => {
+                let (a, b) = self.operands(&nonce);
+                Prompt::Question(format!("What is {a} plus {b}?"))
+            }
+        };
+        Challenge { token, prompt }
+    }
+
+    /// Checks a response, consuming the challenge whether it's right or not, so
+    /// answers can't be guessed one after another.
+    pub fn verify(&self, token: &str, response: &str, now: u64) -> bool {
+        let mut parts = token.splitn(3, '.');
+        let (Some(expires), Some(nonce), Some(mac)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return false;
+        };
+        let Ok(expires) = expires.parse::<u64>() else {
+            return false;
+        };
+        let Some(mac) = unhex(mac) else {
+            return false;
+        };
+        if expires <= now || self.mac(expires, nonce).verify_slice(&mac).is_err() {
+            return false;
+        }
+        // by nonce, the MAC could be spelled in another case to look like a new token
+        {
+            let mut used = self.used.lock().unwrap();
+            if used.len() > PRUNE_THRESHOLD {
+                used.retain(|_, expires| *expires >= now);
+            }
+            if used.insert(nonce.into(), expires).is_some() {
+                return false;
+            }
+        }
+
+        let response = response.trim();
+        match self.cfg.kind {
+            ChallengeKind::ProofOfWork => {
+                response.len() <= MAX_RESPONSE_LENGTH
+                    && leading_zero_bits(&Sha256::digest(format!("{token}:{response}")))
+                        >= self.cfg.difficulty
+            }
+            ChallengeKind::Arithmetic => {
+                let (a, b) = self.operands(nonce);
+                response.parse() == Ok(a + b)
+            }
+        }
+    }
+
+    /// Counts a post towards the rate that makes an address suspicious.
+    pub fn record_post(&self, ip: IpAddr) {
+        let window = self.window();
+        let mut posts = self.posts.lock().unwrap();
+        if posts.len() > PRUNE_THRESHOLD {
+            posts.retain(|_, times| times.back().is_some_and(|t| t.elapsed() < window));
+        }
+        let times = posts.entry(ip).or_default();
+        times.push_back(Instant::now());
+        if times.len() > self.cfg.suspicious_posts {
+            times.pop_front();
+        }
+    }
+
+    /// Whether `ip` posted at least `suspicious_posts` times within the window.
+    pub fn is_suspicious(&self, ip: IpAddr) -> bool {
+        let posts = self.posts.lock().unwrap();
+        posts.get(&ip).is_some_and(|times| {
+            times.len() >= self.cfg.suspicious_posts
+                && times.front().is_some_and(|t| t.elapsed() < self.window())
+        })
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.cfg.suspicious_window_secs)
+    }
+
+    fn mac(&self, expires: u64, nonce: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        let kind = self.cfg.kind.name();
+        let difficulty = self.cfg.difficulty;
+        mac.update(format!("challenge:{kind}:{difficulty}:{expires}:{nonce}").as_bytes());
+        mac
+    }
+
+    fn sign(&self, expires: u64, nonce: &str) -> Vec<u8> {
+        self.mac(expires, nonce).finalize().into_bytes().to_vec()
+    }
+
+    /// The numbers of an arithmetic question, derived from the secret so they
+    /// can't be worked out from the token alone.
+    fn operands(&self, nonce: &str) -> (u32, u32) {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        mac.update(format!("question:{nonce}").as_bytes());
+        let hash = mac.finalize().into_bytes();
+        (u32::from(hash[0] % 20) + 1, u32::from(hash[1] % 20) + 1)
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn challenges(kind: ChallengeKind) -> Challenges {
+        let cfg = AntiSpam {
+            kind,
+            difficulty: 8,
+            suspicious_posts: 3,
+            ..AntiSpam::default()
+        };
+        Challenges::new(cfg, b"secret")
+    }
+
+    fn solve(token: &str, difficulty: u32) -> String {
+        (0u64..)
+            .map(|n| n.to_string())
+            .find(|n| leading_zero_bits(&Sha256::digest(format!("{token}:{n}"))) >= difficulty)
+            .unwrap()
+    }
+
+    #[test]
+    fn proof_of_work() {
+        let challenges = challenges(ChallengeKind::ProofOfWork);
+        let Challenge { token, prompt } = challenges.issue(NOW);
+        let Prompt::ProofOfWork(difficulty) = prompt else {
+            panic!("expected a proof of work prompt");
+        };
+        let solution = solve(&token, difficulty);
+        assert!(!challenges.verify(&token, &solution, NOW + 3600));
+        assert!(challenges.verify(&token, &solution, NOW));
+        // solutions can't be replayed
+        assert!(!challenges.verify(&token, &solution, NOW));
+        let token = challenges.issue(NOW).token;
+        assert!(!challenges.verify(&token, "not it", NOW));
+        assert!(!challenges.verify(&token, &solve(&token, difficulty), NOW));
+    }
+
+    #[test]
+    fn tampered_tokens() {
+        let challenges = challenges(ChallengeKind::ProofOfWork);
+        let token = challenges.issue(NOW).token;
+        let (expires, rest) = token.split_once('.').unwrap();
+        let extended = format!("{}.{rest}", expires.parse::<u64>().unwrap() + 3600);
+        assert!(!challenges.verify(&extended, &solve(&extended, 8), NOW));
+        let other = Challenges::new(AntiSpam::default(), b"other secret");
+        assert!(!other.verify(&token, &solve(&token, 8), NOW));
+        assert!(!challenges.verify("1.2", "0", NOW));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let challenges = challenges(ChallengeKind::Arithmetic);
+        let Challenge { token, prompt } = challenges.issue(NOW);
+        let Prompt::Question(question) = prompt else {
+            panic!("expected a question");
+        };
+        let sum: u32 = question
+            .split(|c: char| !c.is_ascii_digit())
+            .filter_map(|n| n.parse::<u32>().ok())
+            .sum();
+        assert!(challenges.verify(&token, &format!(" {sum} "), NOW));
+        // a wrong answer uses the question up too
+        let token = challenges.issue(NOW).token;
+        assert!(!challenges.verify(&token, "0", NOW));
+        let answers = (2..=40).map(|n| n.to_string());
+        assert!(!answers
+            .into_iter()
+            .any(|n| challenges.verify(&token, &n, NOW)));
+    }
+
+    #[test]
+    fn suspicious_posters() {
+        let challenges = challenges(ChallengeKind::ProofOfWork);
+        let ip = IpAddr::V4(Ipv4Addr::new(172, 20, 0, 1));
+        for _ in 0..2 {
+            challenges.record_post(ip);
+        }
+        assert!(!challenges.required(ChallengeMode::Auto, ip));
+        assert!(challenges.required(ChallengeMode::Always, ip));
+        challenges.record_post(ip);
+        assert!(challenges.required(ChallengeMode::Auto, ip));
+        assert!(!challenges.required(ChallengeMode::Off, ip));
+    }
+}
@@ -1,20 +1,222 @@
 use color_eyre::Result;
+use hmac::{Hmac, Mac};
 use serde::Deserialize;
-use std::{env, fs, net::SocketAddr, path::PathBuf};
+use sha2::Sha256;
+use std::{borrow::Cow, env, fs, net::SocketAddr, path::PathBuf};
+
+use crate::{
+    challenge::ChallengeKind, database::Synchronous, markup::TagSet, templates::models::IpMask,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub log_level: String,
     pub listen: SocketAddr,
+    /// Where images are kept with the default filesystem storage.
     pub image_path: PathBuf,
+    #[serde(default)]
+    pub storage: Storage,
+    /// Where the images of a deleted board are copied to, when the admin asks for it.
+    pub archive_path: Option<PathBuf>,
     pub db: Option<String>,
     pub cookie_secret: String,
+    pub poster_id_salt: Option<String>,
+    pub tripcode_secret: Option<String>,
+    #[serde(default)]
+    pub ip_mask: IpMask,
     pub whois_server: String,
     pub max_upload_size: usize,
     pub max_post_length: usize,
+    #[serde(default)]
+    pub bbcode_tags: TagSet,
+    #[serde(default)]
+    pub security_headers: SecurityHeaders,
+    #[serde(default)]
+    pub csrf: Csrf,
+    #[serde(default)]
+    pub login_limits: LoginLimits,
+    #[serde(default)]
+    pub anti_spam: AntiSpam,
+    #[serde(default)]
+    pub database: Database,
+    #[serde(default)]
+    pub fsck: Fsck,
+    #[serde(default)]
+    pub backup: Backup,
     pub admins: Vec<Admin>,
 }
 
+/// Where images are kept.
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Storage {
+    /// Files in `image_path`.
+    #[default]
+    Filesystem,
+    /// An S3-compatible bucket, which several instances can share.
+    S3(S3),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3 {
+    /// An `https://` URL, or `http://` for a MinIO next to the server.
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Put in front of image names, so the bucket can hold other things too.
+    #[serde(default)]
+    pub prefix: String,
+}
+
+fn default_region() -> String {
+    "us-east-1".into()
+}
+
+/// Tuning for the database executor threads.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct Database {
+    /// Read-only connections serving page views next to the single writer.
+    pub readers: usize,
+    /// Tasks that can wait for the writer, and for the readers, before
+    /// requests are turned away with a 503.
+    pub queue_size: usize,
+    pub synchronous: Synchronous,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self {
+            readers: 4,
+            queue_size: 256,
+            synchronous: Synchronous::default(),
+        }
+    }
+}
+
+/// Backups made while the server runs, like `zhaba backup`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Backup {
+    /// How often to back up, 0 disables the backups.
+    pub interval_secs: u64,
+    pub directory: PathBuf,
+    /// How many backups to keep around, 0 keeps all of them.
+    pub keep: usize,
+}
+
+impl Default for Backup {
+    fn default() -> Self {
+        Self {
+            interval_secs: 0,
+            directory: "backups".into(),
+            keep: 7,
+        }
+    }
+}
+
+/// Periodic checks of the stored images against the posts, like `zhaba fsck`.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct Fsck {
+    /// How often to check, 0 disables the checks.
+    pub interval_secs: u64,
+    /// Fix what's found instead of only logging it.
+    pub repair: bool,
+}
+
+/// The challenge posters solve on boards that ask for one.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AntiSpam {
+    pub kind: ChallengeKind,
+    /// Leading zero bits a proof of work needs, each one doubles the work.
+    pub difficulty: u32,
+    /// How long a challenge can be solved for after the page was loaded.
+    pub ttl_secs: u64,
+    /// Posts from one address within the window that make it suspicious.
+    pub suspicious_posts: usize,
+    pub suspicious_window_secs: u64,
+    /// How long bans from word filters last, 0 bans for good.
+    pub filter_ban_secs: u64,
+}
+
+impl Default for AntiSpam {
+    fn default() -> Self {
+        Self {
+            kind: ChallengeKind::default(),
+            difficulty: 18,
+            ttl_secs: 3600,
+            suspicious_posts: 5,
+            suspicious_window_secs: 600,
+            filter_ban_secs: 7 * 24 * 3600,
+        }
+    }
+}
+
+/// Failed admin login throttling, applied per client IP and per username.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct LoginLimits {
+    /// Failures allowed before the backoff kicks in.
+    pub free_attempts: u32,
+    /// The first backoff delay, doubled on every further failure.
+    pub backoff_base_secs: u64,
+    /// Failures after which logins are locked out entirely.
+    pub max_failures: u32,
+    /// How long a lockout lasts, also caps the backoff.
+    pub lockout_secs: u64,
+}
+
+impl Default for LoginLimits {
+    fn default() -> Self {
+        Self {
+            free_attempts: 3,
+            backoff_base_secs: 1,
+            max_failures: 10,
+            lockout_secs: 900,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Csrf {
+    /// Accept forms without a valid token if their `Origin` (or `Referer`) matches.
+    pub origin_fallback: bool,
+    /// Origins accepted by the fallback, the request's `Host` is used if empty.
+    pub allowed_origins: Vec<String>,
+}
+
+/// Headers added to every response that doesn't set them itself.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SecurityHeaders {
+    pub content_security_policy: String,
+    pub referrer_policy: String,
+    pub frame_options: String,
+    /// Enables `Strict-Transport-Security`, only set this when serving over HTTPS.
+    pub hsts_max_age: Option<u64>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self {
+            // inline styles are used for board colors
+            content_security_policy: "default-src 'none'; script-src 'self'; \
+                style-src 'self' 'unsafe-inline'; img-src 'self'; font-src 'self'; \
+                form-action 'self'; frame-ancestors 'none'; base-uri 'none'"
+                .into(),
+            referrer_policy: "same-origin".into(),
+            frame_options: "DENY".into(),
+            hsts_max_age: None,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Admin {
     pub name: String,
@@ -28,4 +230,31 @@ impl Config {
         let config_str = fs::read_to_string(path)?;
         Ok(toml::from_str(&config_str)?)
     }
+
+    /// Salt for pseudonymous poster IDs, derived from the cookie secret if unset.
+    pub fn poster_id_salt(&self) -> Cow<'_, [u8]> {
+        self.secret_or_derived(self.poster_id_salt.as_deref(), "poster-id")
+    }
+
+    /// Secret for tripcode hashing, derived from the cookie secret if unset.
+    pub fn tripcode_secret(&self) -> Cow<'_, [u8]> {
+        self.secret_or_derived(self.tripcode_secret.as_deref(), "tripcode")
+    }
+
+    /// Key for signing anti-spam challenges, derived from the cookie secret.
+    pub fn challenge_secret(&self) -> Cow<'_, [u8]> {
+        self.secret_or_derived(None, "challenge")
+    }
+
+    /// A key of its own for each `purpose`, so one leaking doesn't give away
+    /// the cookie secret or the others.
+    fn secret_or_derived<'a>(&'a self, secret: Option<&'a str>, purpose: &str) -> Cow<'a, [u8]> {
+        if let Some(secret) = secret {
+            return Cow::Borrowed(secret.as_bytes());
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.cookie_secret.as_bytes())
+            .expect("HMAC accepts any key length");
+        mac.update(purpose.as_bytes());
+        Cow::Owned(mac.finalize().into_bytes().to_vec())
+    }
 }
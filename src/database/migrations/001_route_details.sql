@@ -0,0 +1,2 @@
+alter table posts add column prefix text;
+alter table posts add column descr text;
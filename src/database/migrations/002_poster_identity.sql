@@ -0,0 +1,14 @@
+alter table boards add column identity integer not null default 0;
+alter table posts add column thread integer;
+
+with recursive chain(post, ancestor, parent) as (
+    select id, id, reply from posts
+    union all
+    select chain.post, p.id, p.reply from chain join posts as p on p.id = chain.parent
+)
+update posts set thread = (
+    select ancestor from chain
+    where chain.post = posts.id
+      and chain.ancestor != posts.id
+      and (chain.parent is null or chain.parent not in (select id from posts))
+) where reply is not null;
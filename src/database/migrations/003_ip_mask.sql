@@ -0,0 +1 @@
+alter table boards add column ip_mask integer;
@@ -0,0 +1,2 @@
+alter table posts add column name text;
+alter table posts add column tripcode text;
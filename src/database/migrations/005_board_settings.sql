@@ -0,0 +1,4 @@
+alter table boards add column locked integer not null default 0;
+alter table boards add column images integer not null default 0;
+alter table boards add column max_post_length integer;
+alter table boards add column hidden integer not null default 0;
@@ -0,0 +1 @@
+alter table boards add column tags text;
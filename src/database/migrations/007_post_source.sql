@@ -0,0 +1,2 @@
+-- posts created before this migration hold pre-rendered HTML (format 0)
+alter table posts add column format integer not null default 0;
@@ -0,0 +1,12 @@
+-- admins live in the config file, so they're referenced by name
+create table admin_totp(
+    admin text primary key,
+    secret blob not null,
+    enabled integer not null default 0,
+    last_step integer
+);
+create table admin_recovery_codes(
+    admin text not null,
+    code_hash text not null,
+    primary key (admin, code_hash)
+);
@@ -0,0 +1,2 @@
+-- 0 = off, 1 = auto (only for suspicious posters), 2 = always
+alter table boards add column challenge integer not null default 1;
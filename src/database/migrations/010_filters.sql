@@ -0,0 +1,19 @@
+-- kind: 0 = literal, 1 = regex
+-- action: 0 = replace, 1 = hold for review, 2 = reject, 3 = ban
+create table filters(
+    id integer primary key,
+    pattern text not null,
+    kind integer not null,
+    action integer not null,
+    replacement text not null default ''
+);
+create table bans(
+    id integer primary key,
+    ip text not null,
+    reason text not null,
+    time integer not null default (strftime('%s','now')),
+    expires integer
+);
+create index idx_bans_ip on bans(ip);
+-- held posts are only shown to admins until approved
+alter table posts add column pending integer not null default 0;
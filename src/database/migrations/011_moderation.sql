@@ -0,0 +1,9 @@
+alter table boards add column moderated integer not null default 0;
+-- an ASN like AS4242420000, an address or a network in CIDR notation
+create table untrusted(
+    id integer primary key,
+    entry text not null unique,
+    note text not null default '',
+    time integer not null default (strftime('%s','now'))
+);
+create index idx_posts_pending on posts(pending) where pending = 1;
@@ -0,0 +1,2 @@
+-- posts of boards deleted while foreign keys were off, their images are left to `zhaba fsck`
+delete from posts where board not in (select id from boards);
@@ -0,0 +1,13 @@
+-- identifies this instance in exports, so importing them back finds the originals
+create table instance(id text not null);
+insert into instance(id) values (lower(hex(randomblob(16))));
+-- where imported posts came from, to skip duplicates and to resolve replies
+-- into boards that are imported later
+create table imported_posts(
+    source text not null,
+    original integer not null,
+    post integer not null references posts(id) on delete cascade,
+    reply integer,
+    primary key (source, original)
+);
+create index idx_imported_posts_post on imported_posts(post);
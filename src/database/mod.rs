@@ -1,25 +1,73 @@
 use core::fmt;
-use std::{fs, fs::OpenOptions, io::Write, ops::Range, path::PathBuf, time::Instant};
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
 
 use axum::body::Bytes;
 use chrono::NaiveDateTime;
-use color_eyre::{eyre::eyre, Result};
-use rusqlite::{params, OptionalExtension, Rows};
+use color_eyre::{eyre::eyre, Report};
+use rusqlite::{
+    backup::StepResult,
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    OpenFlags, OptionalExtension, Row, Rows, ToSql,
+};
+use serde::Deserialize;
 use tokio::sync::{
-    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    mpsc::{channel, error::TrySendError, Receiver, Sender},
     oneshot,
 };
 
-use crate::{templates::models, whois::WhoisResult};
+use crate::{
+    config,
+    export::{self, BoardExport, ExportedPost, ImportSummary},
+    markup::TagSet,
+    moderation::Untrusted,
+    storage::Images,
+    templates::models,
+    whois::WhoisResult,
+};
 
 mod queries;
 
+pub type Result<T, E = DbError> = std::result::Result<T, E>;
+
+const FORMAT_HTML: i64 = 0;
+const FORMAT_SOURCE: i64 = 1;
+
+static MIGRATIONS: &[&str] = &[
+    include_str!("migrations/001_route_details.sql"),
+    include_str!("migrations/002_poster_identity.sql"),
+    include_str!("migrations/003_ip_mask.sql"),
+    include_str!("migrations/004_tripcodes.sql"),
+    include_str!("migrations/005_board_settings.sql"),
+    include_str!("migrations/006_board_tags.sql"),
+    include_str!("migrations/007_post_source.sql"),
+    include_str!("migrations/008_admin_totp.sql"),
+    include_str!("migrations/009_board_challenge.sql"),
+    include_str!("migrations/010_filters.sql"),
+    include_str!("migrations/011_moderation.sql"),
+    include_str!("migrations/012_board_leftovers.sql"),
+    include_str!("migrations/013_imports.sql"),
+];
+
 macro_rules! generate_executor {
-    ($($task:ident / $fn:ident, ($db:ident, $($arg:ident: $ty:ty),*) => $ret:ty $handler:block)*) => {
+    ($($access:ident $task:ident / $fn:ident, ($db:ident, $($arg:ident: $ty:ty),*) => $ret:ty $handler:block)*) => {
+        /// Handle to the executor threads. Tasks declared `write` go to the single
+        /// writer connection, `read` tasks to whichever reader is free first.
         #[derive(Clone)]
-        pub struct ExecutorConnection(UnboundedSender<Task>);
+        pub struct ExecutorConnection {
+            writer: Sender<Task>,
+            readers: Sender<Task>,
+        }
 
         #[derive(Debug)]
+        #[allow(clippy::large_enum_variant)]
         enum Task {
             $($task{tx:oneshot::Sender<$ret>,$($arg:$ty,)*}),*
         }
@@ -27,45 +75,201 @@ macro_rules! generate_executor {
         impl ExecutorConnection {
             $(pub async fn $fn(&self, $($arg: $ty),*) -> $ret {
                 let (tx, rx) = oneshot::channel();
-                self.0.send(Task::$task{tx,$($arg),*}).unwrap();
-                rx.await.unwrap()
+                self.$access().try_send(Task::$task{tx,$($arg),*})?;
+                // the sender is dropped without a reply if the task panicked
+                rx.await.map_err(|_| DbError::ExecutorGone)?
             })*
+
+            fn read(&self) -> &Sender<Task> {
+                &self.readers
+            }
+
+            fn write(&self) -> &Sender<Task> {
+                &self.writer
+            }
+        }
+
+        impl Task {
+            fn execute(self, conn: &mut rusqlite::Connection) {
+                let before = Instant::now();
+                tracing::debug!("received task {:?}", self);
+                match self {
+                    $(Task::$task{tx,$($arg),*} => {
+                        let $db = &mut *conn;
+                        let _e = tx.send((||$handler)());
+                    })*
+                }
+                tracing::debug!("task took {}ms", Instant::now().duration_since(before).as_secs_f64() / 1000.0);
+            }
         }
 
         pub struct DbExecutor {
-            rx: UnboundedReceiver<Task>,
+            rx: Receiver<Task>,
             db: rusqlite::Connection,
+            read_rx: Option<Receiver<Task>>,
+            readers: Vec<rusqlite::Connection>,
         }
 
         impl DbExecutor {
-            pub fn create(dbpath: &str) -> rusqlite::Result<(Self, ExecutorConnection)> {
-                let (tx, rx) = unbounded_channel();
-                let db = rusqlite::Connection::open(dbpath)?;
+            /// Opens the writer connection, migrates the database, then opens the
+            /// read-only connections. With none, the writer does the reads.
+            pub fn create(dbpath: &str, options: &config::Database) -> rusqlite::Result<(Self, ExecutorConnection)> {
+                let config::Database { readers, queue_size, synchronous } = *options;
+                let (tx, rx) = channel(queue_size);
+                let mut db = rusqlite::Connection::open(dbpath)?;
                 db.execute_batch(include_str!("schema.sql"))?;
-                tracing::info!("Database connected ({})", dbpath);
-                Ok((Self { rx, db }, ExecutorConnection(tx)))
-            }
-
-            pub fn run(mut self) {
-                while let Some(task) = self.rx.blocking_recv() {
-                    let before = Instant::now();
-                    tracing::debug!("received task {:?}", task);
-                    match task {
-                        $(Task::$task{tx,$($arg),*} => {
-                            let $db = &mut self.db;
-                            let _e = tx.send((||$handler)());
-                        })*
+                db.pragma_update(None, "synchronous", synchronous.pragma())?;
+                migrate(&mut db)?;
+                let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI;
+                let readers = (0..readers)
+                    .map(|_| rusqlite::Connection::open_with_flags(dbpath, flags))
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                let (read_tx, read_rx) = if readers.is_empty() {
+                    (tx.clone(), None)
+                } else {
+                    let (read_tx, read_rx) = channel(queue_size);
+                    (read_tx, Some(read_rx))
+                };
+                tracing::info!("Database connected ({}, {} readers)", dbpath, readers.len());
+                let conn = ExecutorConnection { writer: tx, readers: read_tx };
+                Ok((Self { rx, db, read_rx, readers }, conn))
+            }
+
+            /// Runs the writer and each reader on a thread of its own, returning
+            /// once every `ExecutorConnection` has been dropped.
+            pub fn run(self) {
+                let Self { mut rx, mut db, read_rx, readers } = self;
+                let read_rx = Mutex::new(read_rx);
+                thread::scope(|scope| {
+                    for (i, mut conn) in readers.into_iter().enumerate() {
+                        let read_rx = &read_rx;
+                        thread::Builder::new()
+                            .name(format!("db-reader-{i}"))
+                            .spawn_scoped(scope, move || {
+                                loop {
+                                    // the lock is only held while waiting, so idle readers queue up on it
+                                    let task = read_rx.lock().unwrap().as_mut().and_then(|rx| rx.blocking_recv());
+                                    let Some(task) = task else { break };
+                                    task.execute(&mut conn);
+                                }
+                            })
+                            .expect("failed to spawn a database reader thread");
                     }
-                    tracing::debug!("task took {}ms", Instant::now().duration_since(before).as_secs_f64() / 1000.0);
-                }
+                    // a panicking writer drops its queue, so callers get `ExecutorGone`
+                    // instead of waiting on it
+                    thread::Builder::new()
+                        .name("db-writer".into())
+                        .spawn_scoped(scope, move || {
+                            while let Some(task) = rx.blocking_recv() {
+                                task.execute(&mut db);
+                            }
+                        })
+                        .expect("failed to spawn the database writer thread");
+                });
             }
         }
     };
 }
 
+/// SQLite's `synchronous` setting for the writer connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    /// Leaves syncing to the OS, a power loss can corrupt the database.
+    Off,
+    /// Safe from corruption in WAL mode, but the last posts can be lost on power loss.
+    Normal,
+    /// Every commit is synced before the post is acknowledged.
+    #[default]
+    Full,
+    /// Like `Full`, and also syncs the directory after a rollback journal is
+    /// deleted. Only makes a difference outside WAL mode, at an extra sync per commit.
+    Extra,
+}
+
+impl Synchronous {
+    fn pragma(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Normal => "normal",
+            Self::Full => "full",
+            Self::Extra => "extra",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    /// The task queue is full, the executor can't keep up.
+    Overloaded,
+    /// The executor threads are gone, most likely after a panic.
+    ExecutorGone,
+    Sqlite(rusqlite::Error),
+    Other(Report),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Overloaded => f.write_str("database task queue is full"),
+            Self::ExecutorGone => f.write_str("database executor has stopped"),
+            Self::Sqlite(e) => write!(f, "{e}"),
+            Self::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Sqlite(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Sqlite(e)
+    }
+}
+
+impl From<std::io::Error> for DbError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Other(e.into())
+    }
+}
+
+impl From<Report> for DbError {
+    fn from(e: Report) -> Self {
+        Self::Other(e)
+    }
+}
+
+impl<T> From<TrySendError<T>> for DbError {
+    fn from(e: TrySendError<T>) -> Self {
+        match e {
+            TrySendError::Full(_) => Self::Overloaded,
+            TrySendError::Closed(_) => Self::ExecutorGone,
+        }
+    }
+}
+
+impl DbError {
+    /// Whether the database is fine and the request can simply be retried later.
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, Self::Overloaded | Self::ExecutorGone)
+    }
+
+    /// The constraint violation behind a failed insert, e.g. a duplicate name.
+    pub fn is_constraint_violation(&self) -> bool {
+        matches!(self, Self::Sqlite(rusqlite::Error::SqliteFailure(e, _)) if e.code == rusqlite::ErrorCode::ConstraintViolation)
+    }
+}
+
 pub struct InsertImage {
     pub bytes: Bytes,
-    pub directory: PathBuf,
+    pub images: Images,
     pub filename: String,
 }
 
@@ -73,12 +277,39 @@ impl fmt::Debug for InsertImage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("InsertImage")
             .field("bytes", &"...")
-            .field("directory", &self.directory)
             .field("filename", &self.filename)
             .finish()
     }
 }
 
+#[derive(Debug)]
+pub struct NewPost {
+    pub board: String,
+    pub content: String,
+    pub ip: String,
+    pub whois: Option<WhoisResult>,
+    pub name: Option<String>,
+    pub tripcode: Option<String>,
+    pub reply: Option<u64>,
+    pub image: Option<InsertImage>,
+    pub pending: bool,
+}
+
+pub struct TotpSecret(pub Vec<u8>);
+
+impl fmt::Debug for TotpSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TotpSecret(...)")
+    }
+}
+
+/// An admin's TOTP secret, which only guards logins once `enabled`.
+#[derive(Debug)]
+pub struct AdminTotp {
+    pub secret: TotpSecret,
+    pub enabled: bool,
+}
+
 #[derive(Debug)]
 pub enum CreatePostResult {
     Created,
@@ -86,11 +317,13 @@ pub enum CreatePostResult {
 }
 
 generate_executor! {
-    AddPost / create_post, (db, board: String, content: String, ip: String, whois: Option<WhoisResult>, reply: Option<u64>, image: Option<InsertImage>) => Result<CreatePostResult> {
-        let (asn, mnt) = if let Some(whois) = whois {
-            (Some(whois.asn), Some(whois.mnt))
+    // the image, if there is one, is already stored under `image`
+    write AddPost / insert_post, (db, post: NewPost, image: Option<String>) => Result<CreatePostResult> {
+        let NewPost { board, content, ip, whois, name, tripcode, reply, pending, .. } = post;
+        let (asn, mnt, prefix, descr) = if let Some(whois) = whois {
+            (Some(whois.asn), Some(whois.mnt.join(" ")), whois.prefix, whois.descr)
         } else {
-            (None, None)
+            (None, None, None, None)
         };
         if let Some(reply) = reply {
             let mut stmt = db.prepare_cached(queries::CHECK_REPLY)?;
@@ -99,75 +332,679 @@ generate_executor! {
                 return Ok(CreatePostResult::InvalidReply)
             }
         }
-        if let Some(image) = image {
-            let tx = db.transaction()?;
-            let mut stmt = tx.prepare_cached(queries::INSERT_POST)?;
-            let path = image.directory.join(&image.filename);
-            OpenOptions::new().write(true).truncate(true).create_new(true).open(path)?.write_all(&image.bytes)?;
-            stmt.execute(params![content, Some(image.filename), ip, asn, mnt, reply, board])?;
-            drop(stmt);
-            tx.commit()?;
-        } else {
-            let mut stmt = db.prepare_cached(queries::INSERT_POST)?;
-            stmt.execute(params![content, <Option<String>>::None, ip, asn, mnt, reply, board])?;
-        }
+        let mut stmt = db.prepare_cached(queries::INSERT_POST)?;
+        stmt.execute(params![content, FORMAT_SOURCE, image, ip, asn, mnt, prefix, descr, name, tripcode, reply, board, pending])?;
         Ok(CreatePostResult::Created)
     }
 
-    DeletePost / delete_post, (db, id: i64, imgdir: PathBuf) => Result<bool> {
+    // `None` if there's no such post, otherwise the image left for the caller
+    // to delete now that nothing refers to it
+    write DeletePost / delete_post, (db, id: i64) => Result<Option<Option<String>>> {
+        let mut stmt = db.prepare_cached(queries::DELETE_POST)?;
+        Ok(stmt.query_row([id], |r| r.get(0)).optional()?)
+    }
+
+    // copies the whole database to `dst` in a single step, so concurrent writes
+    // can't make the backup start over
+    read Backup / backup, (db, dst: PathBuf) => Result<()> {
+        let mut out = rusqlite::Connection::open(dst)?;
+        let backup = rusqlite::backup::Backup::new(db, &mut out)?;
+        loop {
+            match backup.step(-1)? {
+                StepResult::Done => break,
+                _ => thread::sleep(Duration::from_millis(50)),
+            }
+        }
+        drop(backup);
+        // a single file, without the WAL it came from
+        out.pragma_update(None, "journal_mode", "delete")?;
+        Ok(())
+    }
+
+    read ExportBoard / export_board, (db, name: String) => Result<Option<BoardExport>> {
+        let mut stmt = db.prepare_cached(queries::SELECT_BOARD_BY_NAME)?;
+        let Some(board) = stmt.query_row([name], board_from_row).optional()? else {
+            return Ok(None);
+        };
+        let instance = db.query_row(queries::SELECT_INSTANCE, [], |r| r.get(0))?;
+        let mut stmt = db.prepare_cached(queries::SELECT_BOARD_EXPORT)?;
+        let posts = stmt.query_map([board.id], |r| Ok(ExportedPost {
+            id: r.get(0)?,
+            time: r.get(1)?,
+            content: r.get(2)?,
+            markup: r.get::<_, i64>(3)? == FORMAT_SOURCE,
+            image: r.get(4)?,
+            ip: r.get(5)?,
+            asn: r.get(6)?,
+            mnt: r.get(7)?,
+            prefix: r.get(8)?,
+            descr: r.get(9)?,
+            name: r.get(10)?,
+            tripcode: r.get(11)?,
+            reply: r.get(12)?,
+            reply_board: r.get(13)?,
+            pending: r.get(14)?,
+        }))?;
+        let posts = posts.collect::<rusqlite::Result<_>>()?;
+        let header = export::Header { version: export::VERSION, instance, board };
+        Ok(Some(BoardExport { header, posts }))
+    }
+
+    // `images` are the names the export's images were already stored under,
+    // the ones the imported posts ended up using are returned with the summary
+    write ImportBoard / import_board, (db, export: BoardExport, name: String, images: HashMap<String, String>) => Result<(ImportSummary, Vec<String>)> {
+        let BoardExport { header, posts } = export;
         let tx = db.transaction()?;
-        let mut stmt = tx.prepare_cached(queries::DELETE_POST)?;
-        let Some(image): Option<Option<String>> = stmt.query_row([id], |r| r.get(0)).optional()? else {
-            return Ok(false)
+        let mut importer = Importer {
+            local: tx.query_row(queries::SELECT_INSTANCE, [], |r| r.get(0))?,
+            source: header.instance,
+            board: 0,
+            ids: HashMap::new(),
+            images,
+            used: Vec::new(),
+            summary: ImportSummary { board: name.clone(), ..ImportSummary::default() },
+            tx: &tx,
+        };
+        let mut stmt = tx.prepare_cached(queries::SELECT_BOARD_BY_NAME)?;
+        importer.board = match stmt.query_row([&name], board_from_row).optional()? {
+            Some(board) => board.id,
+            None => {
+                let (b, s) = (&header.board, header.board.settings);
+                let mut stmt = tx.prepare_cached(queries::INSERT_BOARD)?;
+                stmt.execute(params![name, b.description, b.color, s.identity, s.ip_mask, s.locked, s.images, s.max_post_length, s.hidden, s.tags, s.challenge, s.moderated])?;
+                importer.summary.created = true;
+                tx.last_insert_rowid()
+            }
         };
-        if let Some(image) = image {
-            let path = imgdir.join(image);
-            fs::remove_file(path)?;
-        }
         drop(stmt);
+        for post in posts {
+            importer.import(post)?;
+        }
+        importer.summary.resolved = tx.execute(queries::RESOLVE_IMPORTED_REPLIES, [])?;
+        let Importer { used, mut summary, .. } = importer;
+        if summary.imported == 0 {
+            // only duplicates, a board created for them would stay empty
+            summary.created = false;
+            return Ok((summary, used));
+        }
         tx.commit()?;
-        Ok(true)
+        Ok((summary, used))
+    }
+
+    read GetImages / get_images, (db,) => Result<Vec<(i64, String)>> {
+        let mut stmt = db.prepare_cached(queries::SELECT_IMAGES)?;
+        let images = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        Ok(images.collect::<rusqlite::Result<_>>()?)
     }
 
-    GetPosts / get_posts, (db, board: i64, range: Range<u64>) => Result<Vec<models::Post>> {
+    write ClearImage / clear_image, (db, id: i64, image: String) => Result<bool> {
+        let mut stmt = db.prepare_cached(queries::CLEAR_IMAGE)?;
+        Ok(stmt.execute(params![id, image])? == 1)
+    }
+
+    read GetPosts / get_posts, (db, board: i64, range: Range<u64>, include_pending: bool) => Result<Vec<models::Post>> {
         let mut stmt = db.prepare_cached(queries::SELECT_POSTS_BOARD_RANGE)?;
-        let rows = stmt.query(params![board, range.start, range.end])?;
+        let rows = stmt.query(params![board, range.start, range.end, include_pending])?;
+
+        posts_from_rows(rows)
+    }
+
+    read GetPendingPosts / get_pending_posts, (db,) => Result<Vec<models::Post>> {
+        let mut stmt = db.prepare_cached(queries::SELECT_PENDING_POSTS)?;
+        let rows = stmt.query([])?;
 
         posts_from_rows(rows)
     }
 
-    GetBoards / get_boards, (db,) => rusqlite::Result<Vec<models::Board>> {
+    write ApprovePost / approve_post, (db, id: i64) => Result<bool> {
+        let mut stmt = db.prepare_cached(queries::APPROVE_POST)?;
+        Ok(stmt.execute([id])? == 1)
+    }
+
+    // pending posts and posts on hidden boards are only found for admins
+    read ResolvePosts / resolve_posts, (db, ids: Vec<u64>, admin: bool) => Result<Vec<models::ReplyTo>> {
+        let mut stmt = db.prepare_cached(queries::SELECT_POST_LOCATION)?;
+        let mut resolved = Vec::new();
+        for id in ids {
+            let location = stmt.query_row(params![id, admin], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))).optional()?;
+            if let Some((id, timestamp, board, board_name)) = location {
+                let time = NaiveDateTime::from_timestamp_opt(timestamp, 0)
+                    .ok_or_else(|| eyre!("Invalid timestamp {timestamp}"))?;
+                resolved.push(models::ReplyTo { id, time, board, board_name });
+            }
+        }
+        Ok(resolved)
+    }
+
+    read GetBoards / get_boards, (db,) => Result<Vec<models::Board>> {
         let mut stmt = db.prepare_cached(queries::SELECT_BOARDS)?;
         let mut rows = stmt.query([])?;
         let mut boards = Vec::new();
         while let Some(row) = rows.next()? {
-            boards.push(models::Board { id: row.get(0)?, name: row.get(1)?, description: row.get(2)?, color: row.get(3)? });
+            boards.push(board_from_row(row)?);
         }
         Ok(boards)
     }
 
-    GetBoardByName / get_board_by_name, (db, board: String) => rusqlite::Result<Option<models::Board>> {
+    read GetBoardByName / get_board_by_name, (db, board: String) => Result<Option<models::Board>> {
         let mut stmt = db.prepare_cached(queries::SELECT_BOARD_BY_NAME)?;
-        stmt.query_row([board], |r| Ok(models::Board { id: r.get(0)?, name: r.get(1)?, description: r.get(2)?, color: r.get(3)? })).optional()
+        Ok(stmt.query_row([board], board_from_row).optional()?)
     }
 
-    CreateBoard / create_board, (db, name: String, description: String, color: u32) => rusqlite::Result<()> {
+    write CreateBoard / create_board, (db, name: String, description: String, color: u32, settings: models::BoardSettings) => Result<()> {
         let mut stmt = db.prepare_cached(queries::INSERT_BOARD)?;
-        stmt.execute(params![name, description, color])?;
+        let s = settings;
+        stmt.execute(params![name, description, color, s.identity, s.ip_mask, s.locked, s.images, s.max_post_length, s.hidden, s.tags, s.challenge, s.moderated])?;
         Ok(())
     }
 
-    DeleteBoard / delete_board, (db, id: i64) => rusqlite::Result<()> {
-        let mut stmt = db.prepare_cached(queries::DELETE_BOARD)?;
-        stmt.execute([id])?;
-        Ok(())
+    read GetBoardById / get_board_by_id, (db, id: i64) => Result<Option<models::Board>> {
+        let mut stmt = db.prepare_cached(queries::SELECT_BOARD_BY_ID)?;
+        Ok(stmt.query_row([id], board_from_row).optional()?)
+    }
+
+    // counts a board's posts, and how many of them have an image
+    read CountBoardPosts / count_board_posts, (db, id: i64) => Result<(i64, i64)> {
+        let mut stmt = db.prepare_cached(queries::COUNT_BOARD_POSTS)?;
+        Ok(stmt.query_row([id], |r| Ok((r.get(0)?, r.get(1)?)))?)
     }
 
-    UpdateBoard / update_board, (db, board: models::Board) => rusqlite::Result<()> {
+    // deletes a board with all of its posts, returning the images they had, which
+    // are left for the caller to remove. `None` if there's no such board
+    write DeleteBoard / delete_board, (db, id: i64) => Result<Option<Vec<String>>> {
+        let tx = db.transaction()?;
+        let images = tx
+            .prepare_cached(queries::SELECT_BOARD_IMAGES)?
+            .query_map([id], |r| r.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        tx.prepare_cached(queries::DELETE_BOARD_POSTS)?.execute([id])?;
+        if tx.prepare_cached(queries::DELETE_BOARD)?.execute([id])? == 0 {
+            return Ok(None);
+        }
+        tx.commit()?;
+        Ok(Some(images))
+    }
+
+    write UpdateBoard / update_board, (db, board: models::Board) => Result<()> {
         let mut stmt = db.prepare_cached(queries::UPDATE_BOARD)?;
-        stmt.execute(params![board.name, board.description, board.color, board.id])?;
+        let s = board.settings;
+        stmt.execute(params![board.name, board.description, board.color, s.identity, s.ip_mask, s.locked, s.images, s.max_post_length, s.hidden, s.tags, s.challenge, s.moderated, board.id])?;
+        Ok(())
+    }
+
+    read GetTotp / get_totp, (db, admin: String) => Result<Option<AdminTotp>> {
+        let mut stmt = db.prepare_cached(queries::SELECT_TOTP)?;
+        Ok(stmt.query_row([admin], |r| Ok(AdminTotp { secret: TotpSecret(r.get(0)?), enabled: r.get(1)? })).optional()?)
+    }
+
+    write BeginTotpSetup / begin_totp_setup, (db, admin: String, secret: TotpSecret) => Result<()> {
+        let mut stmt = db.prepare_cached(queries::UPSERT_PENDING_TOTP)?;
+        stmt.execute(params![admin, secret.0])?;
+        Ok(())
+    }
+
+    write EnableTotp / enable_totp, (db, admin: String, step: u64, recovery_hashes: Vec<String>) => Result<()> {
+        let tx = db.transaction()?;
+        tx.execute(queries::ENABLE_TOTP, params![step, admin])?;
+        tx.execute(queries::DELETE_RECOVERY_CODES, [&admin])?;
+        let mut stmt = tx.prepare_cached(queries::INSERT_RECOVERY_CODE)?;
+        for hash in recovery_hashes {
+            stmt.execute(params![admin, hash])?;
+        }
+        drop(stmt);
+        Ok(tx.commit()?)
+    }
+
+    write UseTotpStep / use_totp_step, (db, admin: String, step: u64) => Result<bool> {
+        let mut stmt = db.prepare_cached(queries::USE_TOTP_STEP)?;
+        Ok(stmt.execute(params![step, admin])? == 1)
+    }
+
+    read GetRecoveryCodes / get_recovery_codes, (db, admin: String) => Result<Vec<String>> {
+        let mut stmt = db.prepare_cached(queries::SELECT_RECOVERY_CODES)?;
+        let hashes = stmt.query_map([admin], |r| r.get(0))?.collect::<Result<_, _>>()?;
+        Ok(hashes)
+    }
+
+    write UseRecoveryCode / use_recovery_code, (db, admin: String, hash: String) => Result<bool> {
+        let mut stmt = db.prepare_cached(queries::DELETE_RECOVERY_CODE)?;
+        Ok(stmt.execute(params![admin, hash])? == 1)
+    }
+
+    write ResetTotp / reset_totp, (db, admin: String) => Result<bool> {
+        let tx = db.transaction()?;
+        tx.execute(queries::DELETE_RECOVERY_CODES, [&admin])?;
+        let removed = tx.execute(queries::DELETE_TOTP, [&admin])? == 1;
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    read GetFilters / get_filters, (db,) => Result<Vec<models::Filter>> {
+        let mut stmt = db.prepare_cached(queries::SELECT_FILTERS)?;
+        let filters = stmt.query_map([], |r| Ok(models::Filter {
+            id: r.get(0)?,
+            pattern: r.get(1)?,
+            kind: r.get(2)?,
+            action: r.get(3)?,
+            replacement: r.get(4)?,
+        }))?;
+        Ok(filters.collect::<rusqlite::Result<_>>()?)
+    }
+
+    write CreateFilter / create_filter, (db, filter: models::Filter) => Result<()> {
+        let mut stmt = db.prepare_cached(queries::INSERT_FILTER)?;
+        stmt.execute(params![filter.pattern, filter.kind, filter.action, filter.replacement])?;
+        Ok(())
+    }
+
+    write DeleteFilter / delete_filter, (db, id: i64) => Result<bool> {
+        let mut stmt = db.prepare_cached(queries::DELETE_FILTER)?;
+        Ok(stmt.execute([id])? == 1)
+    }
+
+    read GetBans / get_bans, (db,) => Result<Vec<models::Ban>> {
+        let mut stmt = db.prepare_cached(queries::SELECT_BANS)?;
+        let mut rows = stmt.query([])?;
+        let mut bans = Vec::new();
+        while let Some(row) = rows.next()? {
+            bans.push(ban_from_row(row)?);
+        }
+        Ok(bans)
+    }
+
+    read GetBan / get_ban, (db, ip: String) => Result<Option<models::Ban>> {
+        let mut stmt = db.prepare_cached(queries::SELECT_BAN_BY_IP)?;
+        let mut rows = stmt.query([ip])?;
+        rows.next()?.map(ban_from_row).transpose()
+    }
+
+    write CreateBan / create_ban, (db, ip: String, reason: String, duration_secs: Option<u64>) => Result<()> {
+        let mut stmt = db.prepare_cached(queries::INSERT_BAN)?;
+        stmt.execute(params![ip, reason, duration_secs])?;
+        Ok(())
+    }
+
+    read GetUntrusted / get_untrusted, (db,) => Result<Vec<models::UntrustedPoster>> {
+        let mut stmt = db.prepare_cached(queries::SELECT_UNTRUSTED)?;
+        let mut rows = stmt.query([])?;
+        let mut untrusted = Vec::new();
+        while let Some(row) = rows.next()? {
+            let timestamp = row.get(3)?;
+            let time = NaiveDateTime::from_timestamp_opt(timestamp, 0)
+                .ok_or_else(|| eyre!("Invalid timestamp {timestamp}"))?;
+            untrusted.push(models::UntrustedPoster { id: row.get(0)?, entry: row.get(1)?, note: row.get(2)?, time });
+        }
+        Ok(untrusted)
+    }
+
+    write CreateUntrusted / create_untrusted, (db, entry: Untrusted, note: String) => Result<()> {
+        let mut stmt = db.prepare_cached(queries::INSERT_UNTRUSTED)?;
+        stmt.execute(params![entry, note])?;
+        Ok(())
+    }
+
+    write DeleteUntrusted / delete_untrusted, (db, id: i64) => Result<bool> {
+        let mut stmt = db.prepare_cached(queries::DELETE_UNTRUSTED)?;
+        Ok(stmt.execute([id])? == 1)
+    }
+
+    write DeleteBan / delete_ban, (db, id: i64) => Result<bool> {
+        let mut stmt = db.prepare_cached(queries::DELETE_BAN)?;
+        Ok(stmt.execute([id])? == 1)
+    }
+}
+
+impl ExecutorConnection {
+    /// Stores the post's image, then inserts the post. The image is deleted
+    /// again unless the post goes in, so a failed post doesn't leave an orphan
+    /// behind. Storage is only touched from here, never on the writer thread.
+    pub async fn create_post(&self, mut post: NewPost) -> Result<CreatePostResult> {
+        let Some(InsertImage {
+            bytes,
+            images,
+            filename,
+        }) = post.image.take()
+        else {
+            return self.insert_post(post, None).await;
+        };
+        images.put(&filename, bytes).await?;
+        let result = self.insert_post(post, Some(filename.clone())).await;
+        if !matches!(result, Ok(CreatePostResult::Created)) {
+            if let Err(e) = images.delete(&filename).await {
+                tracing::error!("Failed to remove the image of a failed post {filename}: {e}");
+            }
+        }
+        result
+    }
+}
+
+fn ban_from_row(row: &Row) -> Result<models::Ban> {
+    let timestamp = row.get(3)?;
+    let time = NaiveDateTime::from_timestamp_opt(timestamp, 0)
+        .ok_or_else(|| eyre!("Invalid timestamp {timestamp}"))?;
+    let expires = row
+        .get::<_, Option<i64>>(4)?
+        .map(|ts| {
+            NaiveDateTime::from_timestamp_opt(ts, 0).ok_or_else(|| eyre!("Invalid timestamp {ts}"))
+        })
+        .transpose()?;
+    Ok(models::Ban {
+        id: row.get(0)?,
+        ip: row.get(1)?,
+        reason: row.get(2)?,
+        time,
+        expires,
+    })
+}
+
+/// Inserts the posts of an export, keeping track of where each one ended up.
+struct Importer<'a> {
+    tx: &'a rusqlite::Transaction<'a>,
+    /// Instance ids of this database and of the export.
+    local: String,
+    source: String,
+    board: i64,
+    /// Ids in the export to the ids here.
+    ids: HashMap<i64, i64>,
+    /// Image names in the export to the names they were stored under.
+    images: HashMap<String, String>,
+    used: Vec<String>,
+    summary: ImportSummary,
+}
+
+impl Importer<'_> {
+    fn import(&mut self, post: ExportedPost) -> Result<()> {
+        let mut stmt = self.tx.prepare_cached(queries::SELECT_IMPORTED)?;
+        let mut existing = stmt
+            .query_row(params![self.source, post.id], |r| r.get(0))
+            .optional()?;
+        if existing.is_none() && self.source == self.local {
+            let mut stmt = self.tx.prepare_cached(queries::CHECK_ORIGINAL)?;
+            existing = stmt
+                .query_row(params![post.id, post.time], |_| Ok(post.id))
+                .optional()?;
+        }
+        if let Some(id) = existing {
+            self.ids.insert(post.id, id);
+            self.summary.duplicates += 1;
+            return Ok(());
+        }
+
+        let reply = match post.reply {
+            Some(reply) => {
+                let resolved = self.resolve(reply)?;
+                self.summary.unresolved += usize::from(resolved.is_none());
+                resolved
+            }
+            None => None,
+        };
+        let image = match post.image {
+            Some(image) => {
+                let stored = self.images.get(&image).cloned();
+                self.summary.missing_images += usize::from(stored.is_none());
+                self.used.extend(stored.clone());
+                stored
+            }
+            None => None,
+        };
+        let format = if post.markup {
+            FORMAT_SOURCE
+        } else {
+            FORMAT_HTML
+        };
+        let mut stmt = self.tx.prepare_cached(queries::INSERT_IMPORTED_POST)?;
+        stmt.execute(params![
+            post.content,
+            format,
+            image,
+            post.ip,
+            post.asn,
+            post.mnt,
+            post.prefix,
+            post.descr,
+            post.name,
+            post.tripcode,
+            reply,
+            post.time,
+            self.board,
+            post.pending
+        ])?;
+        let id = self.tx.last_insert_rowid();
+        let mut stmt = self.tx.prepare_cached(queries::INSERT_IMPORTED)?;
+        stmt.execute(params![self.source, post.id, id, post.reply])?;
+        self.ids.insert(post.id, id);
+        self.summary.imported += 1;
         Ok(())
     }
+
+    /// Finds a post replied to in this export, an earlier import, or, for posts
+    /// exported from here, among the originals.
+    fn resolve(&self, original: i64) -> Result<Option<i64>> {
+        if let Some(id) = self.ids.get(&original) {
+            return Ok(Some(*id));
+        }
+        let mut stmt = self.tx.prepare_cached(queries::SELECT_IMPORTED)?;
+        if let Some(id) = stmt
+            .query_row(params![self.source, original], |r| r.get(0))
+            .optional()?
+        {
+            return Ok(Some(id));
+        }
+        if self.source == self.local {
+            let mut stmt = self.tx.prepare_cached(queries::CHECK_REPLY)?;
+            if stmt.exists([original])? {
+                return Ok(Some(original));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn board_from_row(row: &Row) -> rusqlite::Result<models::Board> {
+    Ok(models::Board {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        description: row.get(2)?,
+        color: row.get(3)?,
+        settings: models::BoardSettings {
+            identity: row.get(4)?,
+            ip_mask: row.get(5)?,
+            locked: row.get(6)?,
+            images: row.get(7)?,
+            max_post_length: row.get(8)?,
+            hidden: row.get(9)?,
+            tags: row.get(10)?,
+            challenge: row.get(11)?,
+            moderated: row.get(12)?,
+        },
+    })
+}
+
+impl ToSql for models::IdentityMode {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let value = match self {
+            Self::Full => 0,
+            Self::Maintainer => 1,
+            Self::Daily => 2,
+            Self::Thread => 3,
+        };
+        Ok(ToSqlOutput::from(value))
+    }
+}
+
+impl FromSql for models::IdentityMode {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_i64()? {
+            0 => Ok(Self::Full),
+            1 => Ok(Self::Maintainer),
+            2 => Ok(Self::Daily),
+            3 => Ok(Self::Thread),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
+impl ToSql for models::IpMask {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let value = match self {
+            Self::Show => 0,
+            Self::Truncate => 1,
+            Self::Hide => 2,
+        };
+        Ok(ToSqlOutput::from(value))
+    }
+}
+
+impl FromSql for models::IpMask {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_i64()? {
+            0 => Ok(Self::Show),
+            1 => Ok(Self::Truncate),
+            2 => Ok(Self::Hide),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
+impl ToSql for models::ImagePolicy {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let value = match self {
+            Self::Allowed => 0,
+            Self::Required => 1,
+            Self::Forbidden => 2,
+        };
+        Ok(ToSqlOutput::from(value))
+    }
+}
+
+impl FromSql for models::ImagePolicy {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_i64()? {
+            0 => Ok(Self::Allowed),
+            1 => Ok(Self::Required),
+            2 => Ok(Self::Forbidden),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
+impl ToSql for models::ChallengeMode {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let value = match self {
+            Self::Off => 0,
+            Self::Auto => 1,
+            Self::Always => 2,
+        };
+        Ok(ToSqlOutput::from(value))
+    }
+}
+
+impl FromSql for models::ChallengeMode {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_i64()? {
+            0 => Ok(Self::Off),
+            1 => Ok(Self::Auto),
+            2 => Ok(Self::Always),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
+impl ToSql for models::PatternKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let value = match self {
+            Self::Literal => 0,
+            Self::Regex => 1,
+        };
+        Ok(ToSqlOutput::from(value))
+    }
+}
+
+impl FromSql for models::PatternKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_i64()? {
+            0 => Ok(Self::Literal),
+            1 => Ok(Self::Regex),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
+impl ToSql for models::FilterAction {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let value = match self {
+            Self::Replace => 0,
+            Self::Hold => 1,
+            Self::Reject => 2,
+            Self::Ban => 3,
+        };
+        Ok(ToSqlOutput::from(value))
+    }
+}
+
+impl FromSql for models::FilterAction {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_i64()? {
+            0 => Ok(Self::Replace),
+            1 => Ok(Self::Hold),
+            2 => Ok(Self::Reject),
+            3 => Ok(Self::Ban),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
+impl ToSql for Untrusted {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for Untrusted {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Untrusted::parse(value.as_str()?).map_err(|e| FromSqlError::Other(e.into()))
+    }
+}
+
+impl ToSql for TagSet {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for TagSet {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        TagSet::parse(value.as_str()?).map_err(|e| FromSqlError::Other(e.into()))
+    }
+}
+
+/// Checks a copy of the database made by `backup` before it's restored,
+/// returning the images its posts refer to.
+pub fn check_snapshot(path: &Path) -> Result<Vec<String>> {
+    let db = rusqlite::Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let integrity: String = db.query_row("pragma integrity_check", [], |r| r.get(0))?;
+    if integrity != "ok" {
+        return Err(eyre!("Integrity check failed: {integrity}").into());
+    }
+    let version: usize = db.query_row("pragma user_version", [], |r| r.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(eyre!("The backup is from a newer version of zhaba (schema {version})").into());
+    }
+    let mut stmt = db.prepare(queries::SELECT_IMAGES)?;
+    let images = stmt.query_map([], |r| r.get(1))?;
+    Ok(images.collect::<rusqlite::Result<_>>()?)
+}
+
+fn migrate(db: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+    let version: usize = db.query_row("pragma user_version", [], |r| r.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = db.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        tracing::info!("Applied database migration {}", i + 1);
+    }
+    Ok(())
 }
 
 fn posts_from_rows(mut rows: Rows) -> Result<Vec<models::Post>> {
@@ -176,8 +1013,14 @@ fn posts_from_rows(mut rows: Rows) -> Result<Vec<models::Post>> {
         let timestamp = row.get(7)?;
         let time = NaiveDateTime::from_timestamp_opt(timestamp, 0)
             .ok_or_else(|| eyre!("Invalid timestamp {timestamp}"))?;
-        let whois = if let (Some(asn), Some(mnt)) = (row.get(4)?, row.get(5)?) {
-            Some(WhoisResult { asn, mnt })
+        let mnt: Option<String> = row.get(5)?;
+        let whois = if let (Some(asn), Some(mnt)) = (row.get(4)?, mnt) {
+            Some(WhoisResult {
+                asn,
+                prefix: row.get(13)?,
+                mnt: mnt.split_whitespace().map(str::to_string).collect(),
+                descr: row.get(14)?,
+            })
         } else {
             None
         };
@@ -196,16 +1039,380 @@ fn posts_from_rows(mut rows: Rows) -> Result<Vec<models::Post>> {
             });
         }
 
+        let content: String = row.get(1)?;
+        let format: i64 = row.get(18)?;
+        let (content, source) = if format == FORMAT_HTML {
+            (content, None)
+        } else {
+            (String::new(), Some(content))
+        };
+
         posts.push(models::Post {
             id: row.get(0)?,
-            content: row.get(1)?,
+            content,
+            source,
             image: row.get(2)?,
             ip: row.get(3)?,
             whois,
             reply,
             time,
             board: row.get(8)?,
+            thread: row.get(15)?,
+            name: row.get(16)?,
+            tripcode: row.get(17)?,
+            identity: models::Identity::default(),
+            pending: row.get(19)?,
         });
     }
     Ok(posts)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        rc::Rc,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread::JoinHandle,
+        time::Duration,
+    };
+
+    use rand::{
+        distributions::{Alphanumeric, DistString},
+        thread_rng,
+    };
+
+    use super::*;
+    use crate::storage::Filesystem;
+
+    /// A database file and image directory of its own, removed on drop.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new() -> Self {
+            let name = Alphanumeric.sample_string(&mut thread_rng(), 12);
+            let dir = env::temp_dir().join(format!("zhaba-test-{name}"));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn start(&self, readers: usize) -> (ExecutorConnection, JoinHandle<()>) {
+            let path = self.0.join("zhaba.db3");
+            let options = config::Database {
+                readers,
+                ..config::Database::default()
+            };
+            let (exec, conn) = DbExecutor::create(path.to_str().unwrap(), &options).unwrap();
+            (conn, thread::spawn(move || exec.run()))
+        }
+
+        fn images(&self) -> Images {
+            Images::with_storage(Arc::new(Filesystem::new(self.0.clone())))
+        }
+
+        fn post(&self, content: &str, image_size: usize) -> NewPost {
+            let image = (image_size > 0).then(|| InsertImage {
+                bytes: vec![0; image_size].into(),
+                images: self.images(),
+                filename: Alphanumeric.sample_string(&mut thread_rng(), 32) + ".png",
+            });
+            NewPost {
+                board: "b".into(),
+                content: content.into(),
+                ip: "172.20.0.1".into(),
+                whois: None,
+                name: None,
+                tripcode: None,
+                reply: None,
+                image,
+                pending: false,
+            }
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn create_board(db: &ExecutorConnection) -> i64 {
+        let settings = models::BoardSettings::default();
+        db.create_board("b".into(), String::new(), 0, settings)
+            .await
+            .unwrap();
+        db.get_board_by_name("b".into()).await.unwrap().unwrap().id
+    }
+
+    #[tokio::test]
+    async fn readers_see_committed_writes() {
+        let scratch = Scratch::new();
+        let (db, exec) = scratch.start(2);
+        let board = create_board(&db).await;
+        db.create_post(scratch.post("hello", 16)).await.unwrap();
+        let posts = db
+            .get_posts(board, 0..i64::MAX as u64, false)
+            .await
+            .unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].source.as_deref(), Some("hello"));
+        drop(db);
+        exec.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn images_only_stay_with_committed_posts() {
+        let scratch = Scratch::new();
+        let (db, exec) = scratch.start(0);
+        let storage = scratch.images();
+        let images = || async {
+            storage
+                .list()
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(name, _)| name)
+                .filter(|name| !name.starts_with("zhaba.db3"))
+                .collect::<Vec<_>>()
+        };
+        // the board doesn't exist yet, so the insert fails
+        assert!(db.create_post(scratch.post("orphan", 16)).await.is_err());
+        assert_eq!(images().await, Vec::<String>::new());
+
+        create_board(&db).await;
+        let post = scratch.post("kept", 16);
+        let filename = post.image.as_ref().unwrap().filename.clone();
+        db.create_post(post).await.unwrap();
+        assert_eq!(images().await, [filename]);
+        drop(db);
+        exec.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn deleting_a_board_takes_its_posts() {
+        let scratch = Scratch::new();
+        let (db, exec) = scratch.start(0);
+        let board = create_board(&db).await;
+        let post = scratch.post("with image", 16);
+        let filename = post.image.as_ref().unwrap().filename.clone();
+        db.create_post(post).await.unwrap();
+        db.create_post(scratch.post("without", 0)).await.unwrap();
+        assert_eq!(db.count_board_posts(board).await.unwrap(), (2, 1));
+
+        assert_eq!(db.delete_board(board).await.unwrap(), Some(vec![filename]));
+        assert_eq!(db.count_board_posts(board).await.unwrap(), (0, 0));
+        assert_eq!(db.delete_board(board).await.unwrap(), None);
+        drop(db);
+        exec.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn importing_remaps_ids_and_skips_duplicates() {
+        let (from, to) = (Scratch::new(), Scratch::new());
+        let (db, exec) = from.start(0);
+        create_board(&db).await;
+        db.create_post(from.post("first", 16)).await.unwrap();
+        let mut reply = from.post("reply", 0);
+        reply.reply = Some(1);
+        db.create_post(reply).await.unwrap();
+        let archive = from.0.join("b.tar");
+        let exported = export::export(&db, &from.images(), "b".into(), &archive).await;
+        assert_eq!(exported.unwrap(), Some(2));
+        drop(db);
+        exec.join().unwrap();
+
+        let (db, exec) = to.start(0);
+        // taken ids, so the imported posts can't keep theirs
+        create_board(&db).await;
+        db.create_post(to.post("already here", 0)).await.unwrap();
+        let summary = export::import(&db, &to.images(), &archive, Some("c".into()))
+            .await
+            .unwrap();
+        assert!(summary.created);
+        assert_eq!((summary.imported, summary.duplicates), (2, 0));
+        let board = db.get_board_by_name("c".into()).await.unwrap().unwrap().id;
+        let posts = db
+            .get_posts(board, 0..i64::MAX as u64, false)
+            .await
+            .unwrap();
+        let first = posts.iter().find(|p| p.reply.is_none()).unwrap();
+        let reply = posts.iter().find(|p| p.reply.is_some()).unwrap();
+        assert_ne!(first.id, 1);
+        assert_eq!(reply.reply.as_ref().unwrap().id, first.id);
+        let image = first.image.as_deref().unwrap();
+        assert!(to.images().exists(image).await.unwrap());
+
+        let summary = export::import(&db, &to.images(), &archive, None)
+            .await
+            .unwrap();
+        assert!(!summary.created);
+        assert_eq!((summary.imported, summary.duplicates), (0, 2));
+        drop(db);
+        exec.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn importing_only_reads_staged_images() {
+        let scratch = Scratch::new();
+        let (db, exec) = scratch.start(0);
+        create_board(&db).await;
+        let board = db.get_board_by_name("b".into()).await.unwrap().unwrap();
+        let staging = scratch.0.join("staging");
+        fs::create_dir(&staging).unwrap();
+        fs::write(scratch.0.join("secret.png"), "secret").unwrap();
+        let post = ExportedPost {
+            id: 1,
+            time: 0,
+            content: "outside".into(),
+            markup: true,
+            image: Some("../secret.png".into()),
+            ip: "127.0.0.1".into(),
+            asn: None,
+            mnt: None,
+            prefix: None,
+            descr: None,
+            name: None,
+            tripcode: None,
+            reply: None,
+            reply_board: None,
+            pending: false,
+        };
+        let export = BoardExport {
+            header: export::Header {
+                version: export::VERSION,
+                instance: "elsewhere".into(),
+                board,
+            },
+            posts: vec![post],
+        };
+        let stored = export::store_images(&scratch.images(), &export, &staging)
+            .await
+            .unwrap();
+        assert!(stored.is_empty());
+        let (summary, used) = db.import_board(export, "c".into(), stored).await.unwrap();
+        assert_eq!((summary.imported, summary.missing_images), (1, 1));
+        assert!(used.is_empty());
+        let stored = scratch.images().list().await.unwrap();
+        assert!(stored
+            .iter()
+            .all(|(name, _)| !name.ends_with(".png") || name == "secret.png"));
+        drop(db);
+        exec.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn references_skip_pending_posts_and_hidden_boards() {
+        let scratch = Scratch::new();
+        let (db, exec) = scratch.start(0);
+        create_board(&db).await;
+        db.create_post(scratch.post("public", 0)).await.unwrap();
+        let mut pending = scratch.post("pending", 0);
+        pending.pending = true;
+        db.create_post(pending).await.unwrap();
+        let settings = models::BoardSettings {
+            hidden: true,
+            ..models::BoardSettings::default()
+        };
+        db.create_board("h".into(), String::new(), 0, settings)
+            .await
+            .unwrap();
+        let mut hidden = scratch.post("hidden", 0);
+        hidden.board = "h".into();
+        db.create_post(hidden).await.unwrap();
+
+        let ids =
+            |resolved: Vec<models::ReplyTo>| resolved.iter().map(|r| r.id).collect::<Vec<_>>();
+        let resolved = db.resolve_posts(vec![1, 2, 3], false).await.unwrap();
+        assert_eq!(ids(resolved), [1]);
+        let resolved = db.resolve_posts(vec![1, 2, 3], true).await.unwrap();
+        assert_eq!(ids(resolved), [1, 2, 3]);
+        drop(db);
+        exec.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_queue_and_stopped_executor() {
+        let scratch = Scratch::new();
+        let path = scratch.0.join("zhaba.db3");
+        let options = config::Database {
+            readers: 0,
+            queue_size: 1,
+            ..config::Database::default()
+        };
+        let (exec, db) = DbExecutor::create(path.to_str().unwrap(), &options).unwrap();
+        // nothing runs the executor, so the first task takes up the whole queue
+        let queued = tokio::spawn({
+            let db = db.clone();
+            async move { db.get_boards().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(matches!(db.get_boards().await, Err(DbError::Overloaded)));
+
+        drop(exec);
+        assert!(matches!(queued.await.unwrap(), Err(DbError::ExecutorGone)));
+        assert!(matches!(db.get_boards().await, Err(DbError::ExecutorGone)));
+    }
+
+    /// Page views served while posts with large images are being written, for
+    /// each number of readers. Run with
+    /// `cargo test --release mixed_load -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore = "benchmark"]
+    async fn mixed_load_throughput() {
+        const DURATION: Duration = Duration::from_secs(3);
+        const VIEWERS: usize = 16;
+        const IMAGE_SIZE: usize = 8 << 20;
+
+        for readers in [0, 1, 2, 4, 8] {
+            let scratch = Rc::new(Scratch::new());
+            let (db, exec) = scratch.start(readers);
+            let board = create_board(&db).await;
+            for i in 0..500 {
+                db.create_post(scratch.post(&format!("post {i}"), 0))
+                    .await
+                    .unwrap();
+            }
+
+            let reads = Rc::new(AtomicUsize::new(0));
+            let writes = Rc::new(AtomicUsize::new(0));
+            let deadline = Instant::now() + DURATION;
+            let local = tokio::task::LocalSet::new();
+            for _ in 0..VIEWERS {
+                let (db, reads) = (db.clone(), reads.clone());
+                local.spawn_local(async move {
+                    while Instant::now() < deadline {
+                        db.get_board_by_name("b".into()).await.unwrap();
+                        db.get_posts(board, 0..i64::MAX as u64, false)
+                            .await
+                            .unwrap();
+                        reads.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+            let (poster, written) = (db.clone(), writes.clone());
+            let images = scratch.clone();
+            local.spawn_local(async move {
+                while Instant::now() < deadline {
+                    poster
+                        .create_post(images.post("image", IMAGE_SIZE))
+                        .await
+                        .unwrap();
+                    written.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+            local.await;
+
+            let secs = DURATION.as_secs_f64();
+            println!(
+                "{readers} readers: {:.0} page views/s, {:.1} image posts/s",
+                reads.load(Ordering::Relaxed) as f64 / secs,
+                writes.load(Ordering::Relaxed) as f64 / secs,
+            );
+            drop(db);
+            exec.join().unwrap();
+        }
+    }
+}
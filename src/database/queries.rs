@@ -1,11 +1,63 @@
-pub static INSERT_POST: &str = "insert into posts(content,image,ip,asn,mnt,reply,board) values (?,?,?,?,?,?,(select id from boards where name = ?))";
+pub static INSERT_POST: &str = "insert into posts(content,format,image,ip,asn,mnt,prefix,descr,name,tripcode,reply,thread,board,pending) values (?,?,?,?,?,?,?,?,?,?,?,(select coalesce(thread, id) from posts where id = ?11),(select id from boards where name = ?),?)";
 pub static DELETE_POST: &str = "delete from posts where id = ? returning image";
-pub static SELECT_POSTS_BOARD_RANGE: &str = "select post.id, post.content, post.image, post.ip, post.asn, post.mnt, post.reply, post.time, post.board, reply.id, reply.time, reply.board, reply_board.name from posts as post left join posts as reply on post.reply = reply.id left join boards as reply_board on reply.board = reply_board.id where post.board = ? and post.time between ? and ? order by post.time desc";
+pub static SELECT_POSTS_BOARD_RANGE: &str = "select post.id, post.content, post.image, post.ip, post.asn, post.mnt, post.reply, post.time, post.board, reply.id, reply.time, reply.board, reply_board.name, post.prefix, post.descr, post.thread, post.name, post.tripcode, post.format, post.pending from posts as post left join posts as reply on post.reply = reply.id left join boards as reply_board on reply.board = reply_board.id where post.board = ? and post.time between ? and ? and (post.pending = 0 or ?) order by post.time desc";
+pub static SELECT_PENDING_POSTS: &str = "select post.id, post.content, post.image, post.ip, post.asn, post.mnt, post.reply, post.time, post.board, reply.id, reply.time, reply.board, reply_board.name, post.prefix, post.descr, post.thread, post.name, post.tripcode, post.format, post.pending from posts as post left join posts as reply on post.reply = reply.id left join boards as reply_board on reply.board = reply_board.id where post.pending = 1 order by post.time";
+pub static SELECT_INSTANCE: &str = "select id from instance";
+pub static SELECT_BOARD_EXPORT: &str = "select post.id, post.time, post.content, post.format, post.image, post.ip, post.asn, post.mnt, post.prefix, post.descr, post.name, post.tripcode, post.reply, reply_board.name, post.pending from posts as post left join posts as reply on post.reply = reply.id left join boards as reply_board on reply.board = reply_board.id where post.board = ? order by post.id";
+pub static INSERT_IMPORTED_POST: &str = "insert into posts(content,format,image,ip,asn,mnt,prefix,descr,name,tripcode,reply,thread,time,board,pending) values (?,?,?,?,?,?,?,?,?,?,?11,(select coalesce(thread, id) from posts where id = ?11),?,?,?)";
+pub static SELECT_IMPORTED: &str =
+    "select post from imported_posts where source = ? and original = ?";
+pub static INSERT_IMPORTED: &str =
+    "insert into imported_posts(source, original, post, reply) values (?,?,?,?)";
+pub static CHECK_ORIGINAL: &str = "select 1 from posts where id = ? and time = ?";
+// replies of earlier imports into boards that weren't there yet
+pub static RESOLVE_IMPORTED_REPLIES: &str = "update posts set reply = target.post, thread = (select coalesce(p.thread, p.id) from posts as p where p.id = target.post) from imported_posts as origin join imported_posts as target on target.source = origin.source and target.original = origin.reply where posts.id = origin.post and posts.reply is null";
+pub static SELECT_IMAGES: &str = "select id, image from posts where image is not null";
+pub static CLEAR_IMAGE: &str = "update posts set image = null where id = ? and image = ?";
 pub static CHECK_REPLY: &str = "select 1 from posts where id = ?";
+pub static APPROVE_POST: &str = "update posts set pending = 0 where id = ? and pending = 1";
+pub static SELECT_POST_LOCATION: &str = "select post.id, post.time, post.board, board.name from posts as post join boards as board on post.board = board.id where post.id = ? and ((post.pending = 0 and board.hidden = 0) or ?)";
 
-pub static INSERT_BOARD: &str = "insert into boards(name,description,color) values(?,?,?)";
+pub static INSERT_BOARD: &str = "insert into boards(name,description,color,identity,ip_mask,locked,images,max_post_length,hidden,tags,challenge,moderated) values(?,?,?,?,?,?,?,?,?,?,?,?)";
 pub static DELETE_BOARD: &str = "delete from boards where id = ?";
-pub static SELECT_BOARDS: &str = "select * from boards";
-pub static SELECT_BOARD_BY_NAME: &str = "select * from boards where name = ?";
-pub static UPDATE_BOARD: &str =
-    "update boards set name = ?, description = ?, color = ? where id = ?";
+pub static DELETE_BOARD_POSTS: &str = "delete from posts where board = ?";
+pub static SELECT_BOARD_IMAGES: &str =
+    "select image from posts where board = ? and image is not null";
+pub static COUNT_BOARD_POSTS: &str = "select count(*), count(image) from posts where board = ?";
+pub static SELECT_BOARDS: &str =
+    "select id, name, description, color, identity, ip_mask, locked, images, max_post_length, hidden, tags, challenge, moderated from boards";
+pub static SELECT_BOARD_BY_NAME: &str =
+    "select id, name, description, color, identity, ip_mask, locked, images, max_post_length, hidden, tags, challenge, moderated from boards where name = ?";
+pub static SELECT_BOARD_BY_ID: &str =
+    "select id, name, description, color, identity, ip_mask, locked, images, max_post_length, hidden, tags, challenge, moderated from boards where id = ?";
+pub static UPDATE_BOARD: &str = "update boards set name = ?, description = ?, color = ?, identity = ?, ip_mask = ?, locked = ?, images = ?, max_post_length = ?, hidden = ?, tags = ?, challenge = ?, moderated = ? where id = ?";
+
+pub static SELECT_TOTP: &str = "select secret, enabled from admin_totp where admin = ?";
+pub static UPSERT_PENDING_TOTP: &str = "insert into admin_totp(admin, secret) values(?, ?) on conflict(admin) do update set secret = excluded.secret where enabled = 0";
+pub static ENABLE_TOTP: &str = "update admin_totp set enabled = 1, last_step = ? where admin = ?";
+pub static USE_TOTP_STEP: &str = "update admin_totp set last_step = ?1 where admin = ?2 and enabled = 1 and (last_step is null or last_step < ?1)";
+pub static DELETE_TOTP: &str = "delete from admin_totp where admin = ?";
+pub static SELECT_RECOVERY_CODES: &str =
+    "select code_hash from admin_recovery_codes where admin = ?";
+pub static INSERT_RECOVERY_CODE: &str =
+    "insert into admin_recovery_codes(admin, code_hash) values(?, ?)";
+pub static DELETE_RECOVERY_CODE: &str =
+    "delete from admin_recovery_codes where admin = ? and code_hash = ?";
+pub static DELETE_RECOVERY_CODES: &str = "delete from admin_recovery_codes where admin = ?";
+
+pub static SELECT_FILTERS: &str =
+    "select id, pattern, kind, action, replacement from filters order by id";
+pub static INSERT_FILTER: &str =
+    "insert into filters(pattern, kind, action, replacement) values(?, ?, ?, ?)";
+pub static DELETE_FILTER: &str = "delete from filters where id = ?";
+
+pub static SELECT_BANS: &str = "select id, ip, reason, time, expires from bans where expires is null or expires > strftime('%s','now') order by time desc";
+pub static SELECT_BAN_BY_IP: &str = "select id, ip, reason, time, expires from bans where ip = ? and (expires is null or expires > strftime('%s','now')) order by expires is null desc, expires desc limit 1";
+pub static INSERT_BAN: &str =
+    "insert into bans(ip, reason, expires) values(?, ?, strftime('%s','now') + ?)";
+pub static DELETE_BAN: &str = "delete from bans where id = ?";
+
+pub static SELECT_UNTRUSTED: &str =
+    "select id, entry, note, time from untrusted order by time desc";
+pub static INSERT_UNTRUSTED: &str = "insert into untrusted(entry, note) values(?, ?)";
+pub static DELETE_UNTRUSTED: &str = "delete from untrusted where id = ?";
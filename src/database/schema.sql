@@ -1,5 +1,5 @@
 pragma journal_mode = wal;
-pragma synchronous = 0;
+pragma foreign_keys = on;
 
 create table if not exists posts(
     id integer primary key,
@@ -0,0 +1,296 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backup, database::ExecutorConnection, imghdr, storage::Images, tar, templates::models::Board,
+};
+
+pub const VERSION: u32 = 1;
+const HEADER: &str = "board.json";
+const POSTS: &str = "posts.jsonl";
+const IMAGES: &str = "images/";
+
+/// `board.json`, describing the board and where it was exported from.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u32,
+    /// Random id of the exporting instance, see the `instance` table.
+    pub instance: String,
+    pub board: Board,
+}
+
+/// A line of `posts.jsonl`, holding a post the way it's stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedPost {
+    pub id: i64,
+    /// Unix timestamp.
+    pub time: i64,
+    pub content: String,
+    /// Whether `content` is markup, or pre-rendered HTML for old posts.
+    pub markup: bool,
+    pub image: Option<String>,
+    pub ip: String,
+    pub asn: Option<u32>,
+    pub mnt: Option<String>,
+    pub prefix: Option<String>,
+    pub descr: Option<String>,
+    pub name: Option<String>,
+    pub tripcode: Option<String>,
+    /// Id of the post replied to, on the exporting instance.
+    pub reply: Option<i64>,
+    /// Board of the post replied to, if it still existed.
+    pub reply_board: Option<String>,
+    pub pending: bool,
+}
+
+#[derive(Debug)]
+pub struct BoardExport {
+    pub header: Header,
+    pub posts: Vec<ExportedPost>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub board: String,
+    /// Whether the board had to be created, rather than being merged into.
+    pub created: bool,
+    pub imported: usize,
+    /// Posts that were already here, from an earlier import or as the originals.
+    pub duplicates: usize,
+    /// Replies to posts that aren't here (yet), they're filled in if the board
+    /// holding them gets imported later.
+    pub unresolved: usize,
+    /// Replies of earlier imports that this import resolved.
+    pub resolved: usize,
+    pub missing_images: usize,
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.imported == 0 {
+            write!(f, "Nothing new to import into /{}/", self.board)?;
+        } else {
+            let new = if self.created { "the new board " } else { "" };
+            write!(
+                f,
+                "Imported {} posts into {new}/{}/",
+                self.imported, self.board
+            )?;
+        }
+        if self.duplicates != 0 {
+            write!(f, ", skipped {} duplicates", self.duplicates)?;
+        }
+        if self.unresolved != 0 {
+            write!(
+                f,
+                ", {} replies point to posts that aren't here",
+                self.unresolved
+            )?;
+        }
+        if self.resolved != 0 {
+            write!(f, ", resolved {} replies of earlier imports", self.resolved)?;
+        }
+        if self.missing_images != 0 {
+            write!(f, ", {} images were missing", self.missing_images)?;
+        }
+        Ok(())
+    }
+}
+
+/// Exports a board with its posts and images into a tarball at `out`, returning
+/// how many posts went in. `None` if there's no such board.
+pub async fn export(
+    db: &ExecutorConnection,
+    images: &Images,
+    board: String,
+    out: &Path,
+) -> Result<Option<usize>> {
+    let Some(export) = db.export_board(board).await? else {
+        return Ok(None);
+    };
+    let posts = export.posts.len();
+    tokio::task::spawn_blocking({
+        let (images, out) = (images.clone(), out.to_owned());
+        move || write_archive(&export, &images, &out)
+    })
+    .await??;
+    Ok(Some(posts))
+}
+
+fn write_archive(export: &BoardExport, images: &Images, out: &Path) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut builder = tar::Builder::new(BufWriter::new(File::create(out)?));
+    let header = serde_json::to_vec_pretty(&export.header)?;
+    builder.append(HEADER, header.len() as u64, now, &header[..])?;
+    let mut posts = Vec::new();
+    for post in &export.posts {
+        serde_json::to_writer(&mut posts, post)?;
+        posts.push(b'\n');
+    }
+    builder.append(POSTS, posts.len() as u64, now, &posts[..])?;
+    for image in export.posts.iter().filter_map(|p| p.image.as_ref()) {
+        let Some((size, data)) = images.open_blocking(image)? else {
+            tracing::warn!("Leaving the missing image {image} out of the export");
+            continue;
+        };
+        builder.append(&format!("{IMAGES}{image}"), size, now, data)?;
+    }
+    builder.finish()?.into_inner().map_err(|e| e.into_error())?;
+    Ok(())
+}
+
+/// Imports an exported board, under `name` or the name it was exported with,
+/// merging it into the board of that name if there is one.
+pub async fn import(
+    db: &ExecutorConnection,
+    images: &Images,
+    archive: &Path,
+    name: Option<String>,
+) -> Result<ImportSummary> {
+    let suffix = Alphanumeric.sample_string(&mut thread_rng(), 8);
+    let staging = std::env::temp_dir().join(format!("zhaba-import-{suffix}"));
+    let export = tokio::task::spawn_blocking({
+        let (archive, staging) = (archive.to_owned(), staging.clone());
+        move || read_archive(&archive, &staging)
+    })
+    .await?;
+    let result = match export {
+        Ok(export) => store_and_import(db, images, export, name, &staging).await,
+        Err(e) => Err(e),
+    };
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+async fn store_and_import(
+    db: &ExecutorConnection,
+    images: &Images,
+    export: BoardExport,
+    name: Option<String>,
+    staging: &Path,
+) -> Result<ImportSummary> {
+    let name = name.unwrap_or_else(|| export.header.board.name.clone());
+    let stored = store_images(images, &export, staging).await?;
+    let result = db.import_board(export, name, stored.clone()).await;
+    // images of posts that were already here, or of an import that failed
+    let used: HashSet<_> = match &result {
+        Ok((_, used)) => used.iter().collect(),
+        Err(_) => HashSet::new(),
+    };
+    for image in stored.values().filter(|i| !used.contains(i)) {
+        if let Err(e) = images.delete(image).await {
+            tracing::warn!("Failed to remove the unused imported image {image}: {e}");
+        }
+    }
+    Ok(result?.0)
+}
+
+/// Stores the staged images of an export under names that aren't taken yet,
+/// returning the names they went in under by the ones in the export. The names
+/// come from the export, so only ones like ours are looked for.
+pub async fn store_images(
+    images: &Images,
+    export: &BoardExport,
+    staging: &Path,
+) -> Result<HashMap<String, String>> {
+    let mut stored = HashMap::new();
+    for image in export.posts.iter().filter_map(|p| p.image.as_ref()) {
+        if stored.contains_key(image) || !imghdr::is_image_name(image) {
+            continue;
+        }
+        let data = match tokio::fs::read(staging.join(image)).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match store_image(images, image, data).await {
+            Ok(name) => stored.insert(image.clone(), name),
+            Err(e) => {
+                for name in stored.values() {
+                    let _ = images.delete(name).await;
+                }
+                return Err(e.into());
+            }
+        };
+    }
+    Ok(stored)
+}
+
+async fn store_image(images: &Images, image: &str, data: Vec<u8>) -> io::Result<String> {
+    let mut name = image.to_owned();
+    while images.exists(&name).await? {
+        name = rename_image(image);
+    }
+    images.put(&name, data.into()).await?;
+    Ok(name)
+}
+
+fn read_archive(archive: &Path, staging: &Path) -> Result<BoardExport> {
+    fs::create_dir_all(staging)?;
+    let mut archive = tar::Archive::new(BufReader::new(File::open(archive)?));
+    let mut header = None;
+    let mut posts = None;
+    while let Some(mut entry) = archive.next_entry()? {
+        let name = entry.name.clone();
+        if name == HEADER {
+            let parsed: Header = serde_json::from_reader(&mut entry)?;
+            if parsed.version != VERSION {
+                return Err(eyre!("Unsupported export version {}", parsed.version));
+            }
+            header = Some(parsed);
+        } else if name == POSTS {
+            let mut parsed = Vec::new();
+            for line in BufReader::new(entry).lines() {
+                let line = line?;
+                if !line.is_empty() {
+                    parsed.push(serde_json::from_str(&line)?);
+                }
+            }
+            posts = Some(parsed);
+        } else if let Some(image) = name
+            .strip_prefix(IMAGES)
+            .filter(|i| backup::is_plain_name(i))
+        {
+            let mut file = File::create(staging.join(image))?;
+            io::copy(&mut entry, &mut file)?;
+        } else {
+            return Err(eyre!("Unexpected file {name:?} in the export"));
+        }
+    }
+    let header = header.ok_or_else(|| eyre!("The export has no {HEADER}"))?;
+    let mut posts: Vec<ExportedPost> = posts.ok_or_else(|| eyre!("The export has no {POSTS}"))?;
+    // replies come after the posts they reply to
+    posts.sort_unstable_by_key(|p| p.id);
+    Ok(BoardExport { header, posts })
+}
+
+/// A fresh name for an imported image that clashes with one already here.
+pub fn rename_image(image: &str) -> String {
+    let mut name = Alphanumeric.sample_string(&mut thread_rng(), 32);
+    if let Some(ext) = Path::new(image).extension().and_then(|e| e.to_str()) {
+        name.push('.');
+        name.push_str(ext);
+    }
+    name
+}
+
+/// A temporary file for exports on their way to or from an admin's browser.
+pub fn scratch_path() -> PathBuf {
+    let name = Alphanumeric.sample_string(&mut thread_rng(), 16);
+    std::env::temp_dir().join(format!("zhaba-{name}.tar"))
+}
@@ -0,0 +1,142 @@
+use std::sync::{Arc, Mutex};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    database::{DbError, ExecutorConnection},
+    templates::models::{Filter, FilterAction, PatternKind},
+};
+
+// keeps an admin's pattern from compiling into something huge
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Compiles a filter's pattern, both kinds match case-insensitively.
+pub fn compile(kind: PatternKind, pattern: &str) -> Result<Regex, regex::Error> {
+    let pattern = match kind {
+        PatternKind::Literal => regex::escape(pattern),
+        PatternKind::Regex => pattern.into(),
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+}
+
+/// The outcome of running a post through the filters.
+#[derive(Debug)]
+pub struct Verdict {
+    /// The most severe action of the matching filters, besides replacements.
+    pub action: Option<FilterAction>,
+    /// The post content with every replacement applied.
+    pub content: String,
+    pub matched: Vec<Filter>,
+}
+
+/// A compiled snapshot of the filter table.
+pub struct FilterSet {
+    filters: Vec<(Filter, Regex)>,
+}
+
+impl FilterSet {
+    pub fn new(filters: Vec<Filter>) -> Self {
+        let filters = filters
+            .into_iter()
+            .filter_map(|filter| match compile(filter.kind, &filter.pattern) {
+                Ok(regex) => Some((filter, regex)),
+                Err(e) => {
+                    tracing::warn!("Skipping filter {}: {e}", filter.id);
+                    None
+                }
+            })
+            .collect();
+        Self { filters }
+    }
+
+    /// Checks the filters in order, later ones seeing the replacements of earlier ones.
+    pub fn apply(&self, content: &str) -> Verdict {
+        let mut verdict = Verdict {
+            action: None,
+            content: content.into(),
+            matched: Vec::new(),
+        };
+        for (filter, regex) in &self.filters {
+            if !regex.is_match(&verdict.content) {
+                continue;
+            }
+            if filter.action == FilterAction::Replace {
+                let replaced =
+                    regex.replace_all(&verdict.content, regex::NoExpand(&filter.replacement));
+                verdict.content = replaced.into_owned();
+            } else {
+                verdict.action = verdict.action.max(Some(filter.action));
+            }
+            verdict.matched.push(filter.clone());
+        }
+        verdict
+    }
+}
+
+/// Keeps the compiled filters around until an admin changes them.
+#[derive(Default)]
+pub struct Filters {
+    cached: Mutex<Option<Arc<FilterSet>>>,
+}
+
+impl Filters {
+    pub async fn get(&self, db: &ExecutorConnection) -> Result<Arc<FilterSet>, DbError> {
+        if let Some(set) = &*self.cached.lock().unwrap() {
+            return Ok(set.clone());
+        }
+        let set = Arc::new(FilterSet::new(db.get_filters().await?));
+        *self.cached.lock().unwrap() = Some(set.clone());
+        Ok(set)
+    }
+
+    pub fn invalidate(&self) {
+        *self.cached.lock().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(id: i64, kind: PatternKind, pattern: &str, action: FilterAction) -> Filter {
+        Filter {
+            id,
+            pattern: pattern.into(),
+            kind,
+            action,
+            replacement: "[removed]".into(),
+        }
+    }
+
+    #[test]
+    fn replacements_and_severity() {
+        let set = FilterSet::new(vec![
+            filter(1, PatternKind::Literal, "c.ash", FilterAction::Replace),
+            filter(2, PatternKind::Regex, r"buy\s+now", FilterAction::Hold),
+            filter(3, PatternKind::Literal, "scam", FilterAction::Reject),
+            filter(4, PatternKind::Regex, "(", FilterAction::Ban),
+        ]);
+        let verdict = set.apply("C.ASH cash, BUY  now");
+        assert_eq!(verdict.content, "[removed] cash, BUY  now");
+        assert_eq!(verdict.action, Some(FilterAction::Hold));
+        assert_eq!(verdict.matched.len(), 2);
+
+        let verdict = set.apply("buy now, not a scam");
+        assert_eq!(verdict.action, Some(FilterAction::Reject));
+
+        let verdict = set.apply("hello");
+        assert_eq!(verdict.action, None);
+        assert!(verdict.matched.is_empty());
+    }
+
+    #[test]
+    fn replacement_is_literal() {
+        let mut replace = filter(1, PatternKind::Regex, "(a)", FilterAction::Replace);
+        replace.replacement = "$1$$".into();
+        let set = FilterSet::new(vec![replace]);
+        assert_eq!(set.apply("bab").content, "b$1$$b");
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::Result;
+
+use crate::{database::ExecutorConnection, imghdr, storage::Images};
+
+// younger files may belong to a post that's still being committed
+const GRACE_PERIOD: Duration = Duration::from_secs(600);
+
+/// Inconsistencies between the stored images and the posts table.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Files no post refers to.
+    pub orphans: Vec<String>,
+    /// Posts whose image file is gone, with the file name they refer to.
+    pub missing: Vec<(i64, String)>,
+    /// Staging files left behind by a write that never finished.
+    pub stale: Vec<String>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.orphans.is_empty() && self.missing.is_empty() && self.stale.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for name in &self.orphans {
+            writeln!(f, "orphaned image {name}")?;
+        }
+        for (post, name) in &self.missing {
+            writeln!(f, "post #{post} is missing its image {name}")?;
+        }
+        for name in &self.stale {
+            writeln!(f, "stale staging file {name}")?;
+        }
+        write!(
+            f,
+            "{} orphaned images, {} posts with missing images, {} stale staging files",
+            self.orphans.len(),
+            self.missing.len(),
+            self.stale.len()
+        )
+    }
+}
+
+pub async fn check(db: &ExecutorConnection, images: &Images) -> Result<Report> {
+    // files first, so a post committed in between can't make its image look orphaned
+    let files = images.list().await?;
+    let referenced = db.get_images().await?;
+    let mut report = compare(&files, &referenced, SystemTime::now());
+    // but a post committed in between would have its image look missing
+    let mut missing = Vec::with_capacity(report.missing.len());
+    for (post, name) in report.missing {
+        if !images.exists(&name).await? {
+            missing.push((post, name));
+        }
+    }
+    report.missing = missing;
+    Ok(report)
+}
+
+/// Deletes the orphaned and stale files and clears the image of posts whose
+/// file is still missing.
+pub async fn repair(db: &ExecutorConnection, images: &Images, report: &Report) -> Result<()> {
+    for name in &report.orphans {
+        images.delete(name).await?;
+        tracing::info!("Removed orphaned image {name}");
+    }
+    for name in &report.stale {
+        images.delete(name).await?;
+        tracing::info!("Removed stale staging file {name}");
+    }
+    for (post, name) in &report.missing {
+        if images.exists(name).await? {
+            continue;
+        }
+        if db.clear_image(*post, name.clone()).await? {
+            tracing::info!("Cleared the missing image {name} of post #{post}");
+        }
+    }
+    Ok(())
+}
+
+/// Anything listed that isn't an image or a staging file for one is left alone,
+/// it may not be ours.
+fn compare(files: &[(String, SystemTime)], images: &[(i64, String)], now: SystemTime) -> Report {
+    let referenced: HashSet<_> = images.iter().map(|(_, name)| name.as_str()).collect();
+    let present: HashSet<_> = files.iter().map(|(name, _)| name.as_str()).collect();
+    let old: Vec<_> = files
+        .iter()
+        .filter(|(_, modified)| now.duration_since(*modified).unwrap_or_default() >= GRACE_PERIOD)
+        .map(|(name, _)| name)
+        .collect();
+    let mut orphans: Vec<_> = old
+        .iter()
+        .filter(|name| imghdr::is_image_name(name) && !referenced.contains(name.as_str()))
+        .map(|name| name.to_string())
+        .collect();
+    let mut stale: Vec<_> = old
+        .iter()
+        .filter(|name| staged_name(name).is_some_and(imghdr::is_image_name))
+        .map(|name| name.to_string())
+        .collect();
+    let mut missing: Vec<_> = images
+        .iter()
+        .filter(|(_, name)| !present.contains(name.as_str()))
+        .cloned()
+        .collect();
+    orphans.sort_unstable();
+    missing.sort_unstable();
+    stale.sort_unstable();
+    Report {
+        orphans,
+        missing,
+        stale,
+    }
+}
+
+/// The image a staging file is being written for, they're `.{name}.tmp`.
+fn staged_name(name: &str) -> Option<&str> {
+    name.strip_prefix('.')?.strip_suffix(".tmp")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEPT: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.png";
+    const ORPHAN: &str = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb.jpg";
+    const NEW: &str = "cccccccccccccccccccccccccccccccc.gif";
+    const MISSING: &str = "dddddddddddddddddddddddddddddddd.png";
+
+    #[test]
+    fn finds_both_directions() {
+        let now = SystemTime::now();
+        let old = now - GRACE_PERIOD * 2;
+        let files = [
+            (KEPT.to_string(), old),
+            (ORPHAN.to_string(), old),
+            (format!(".{ORPHAN}.tmp"), old),
+            (format!(".{NEW}.tmp"), now),
+            (NEW.to_string(), now),
+            ("unrelated.txt".to_string(), old),
+            (".unrelated.tmp".to_string(), old),
+        ];
+        let images = [(2, MISSING.to_string()), (1, KEPT.to_string())];
+        let report = compare(&files, &images, now);
+        assert_eq!(
+            report,
+            Report {
+                orphans: vec![ORPHAN.into()],
+                missing: vec![(2, MISSING.into())],
+                stale: vec![format!(".{ORPHAN}.tmp")],
+            }
+        );
+        assert!(!report.is_clean());
+        assert!(compare(&files[..1], &images[1..], now).is_clean());
+    }
+}
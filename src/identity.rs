@@ -0,0 +1,252 @@
+use std::net::IpAddr;
+
+use axum_sessions::async_session::base64::{self, URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::templates::models::{Board, Identity, IdentityMode, IpMask, Post};
+
+const POSTER_ID_LEN: usize = 8;
+const TRIPCODE_LEN: usize = 10;
+
+/// Fills in `Post::identity` according to the board's display mode, then masks
+/// `Post::ip` and the whois prefix so that nothing downstream sees more than it should.
+pub fn assign(board: &Board, posts: &mut [Post], secret: &[u8], mask: IpMask) {
+    for post in posts {
+        post.identity = match board.settings.identity {
+            IdentityMode::Full => Identity::Full,
+            IdentityMode::Maintainer => Identity::Maintainer,
+            IdentityMode::Daily => {
+                let scope = format!("{}/{}", board.id, post.time.date());
+                Identity::Pseudonym(poster_id(secret, &scope, &post.ip))
+            }
+            IdentityMode::Thread => {
+                let scope = post.thread.unwrap_or(post.id).to_string();
+                Identity::Pseudonym(poster_id(secret, &scope, &post.ip))
+            }
+        };
+        mask_ip(&mut post.ip, mask);
+        if let Some(whois) = &mut post.whois {
+            mask_prefix(&mut whois.prefix, mask);
+        }
+    }
+}
+
+/// Splits a `name#password` field into the display name and its tripcode.
+pub fn parse_name(input: &str, secret: &[u8]) -> (Option<String>, Option<String>) {
+    let (name, password) = match input.split_once('#') {
+        Some((name, password)) => (name.trim(), Some(password)),
+        None => (input.trim(), None),
+    };
+    let name = (!name.is_empty()).then(|| name.to_string());
+    let tripcode = password
+        .filter(|p| !p.is_empty())
+        .map(|p| tripcode(secret, p));
+    (name, tripcode)
+}
+
+pub fn tripcode(secret: &[u8], password: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(password.as_bytes());
+    let mut trip = base64::encode_config(mac.finalize().into_bytes(), URL_SAFE_NO_PAD);
+    trip.truncate(TRIPCODE_LEN);
+    trip
+}
+
+/// Rewrites `ip` in place. Hidden addresses become an empty string.
+pub fn mask_ip(ip: &mut String, mask: IpMask) {
+    match mask {
+        IpMask::Show => {}
+        IpMask::Hide => ip.clear(),
+        IpMask::Truncate => {
+            *ip = match ip.parse() {
+                Ok(IpAddr::V4(v4)) => {
+                    let [a, b, c, _] = v4.octets();
+                    format!("{a}.{b}.{c}.0/24")
+                }
+                Ok(IpAddr::V6(v6)) => {
+                    let [a, b, c, ..] = v6.segments();
+                    format!("{a:x}:{b:x}:{c:x}::/48")
+                }
+                Err(_) => String::new(),
+            }
+        }
+    }
+}
+
+/// Masks a route prefix along with the address in it. Truncated prefixes are
+/// cut down to the same /24 or /48 as addresses, shorter ones are kept.
+pub fn mask_prefix(prefix: &mut Option<String>, mask: IpMask) {
+    match mask {
+        IpMask::Show => {}
+        IpMask::Hide => *prefix = None,
+        IpMask::Truncate => {
+            *prefix = prefix.take().and_then(|prefix| {
+                let (address, len) = prefix.split_once('/')?;
+                let len: u8 = len.parse().ok()?;
+                let (address, max_len) = match address.parse().ok()? {
+                    IpAddr::V4(v4) => (v4.to_string(), 24),
+                    IpAddr::V6(v6) => (v6.to_string(), 48),
+                };
+                if len <= max_len {
+                    return Some(prefix);
+                }
+                let mut truncated = address;
+                mask_ip(&mut truncated, IpMask::Truncate);
+                Some(truncated)
+            });
+        }
+    }
+}
+
+pub fn poster_id(secret: &[u8], scope: &str, ip: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(scope.as_bytes());
+    mac.update(b"\0");
+    mac.update(ip.as_bytes());
+    let mut id = base64::encode_config(mac.finalize().into_bytes(), URL_SAFE_NO_PAD);
+    id.truncate(POSTER_ID_LEN);
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use askama::Template;
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::{
+        templates::{models::BoardSettings, BoardView},
+        whois::WhoisResult,
+    };
+
+    #[test]
+    fn poster_id_is_stable_per_scope() {
+        let a = poster_id(b"secret", "1", "172.20.0.1");
+        assert_eq!(a.len(), POSTER_ID_LEN);
+        assert_eq!(a, poster_id(b"secret", "1", "172.20.0.1"));
+        assert_ne!(a, poster_id(b"secret", "2", "172.20.0.1"));
+        assert_ne!(a, poster_id(b"secret", "1", "172.20.0.2"));
+        assert_ne!(a, poster_id(b"other", "1", "172.20.0.1"));
+    }
+
+    #[test]
+    fn parse_name_tripcodes() {
+        let trip = tripcode(b"secret", "hunter2");
+        assert_eq!(trip.len(), TRIPCODE_LEN);
+        assert_ne!(trip, tripcode(b"other", "hunter2"));
+        assert_eq!(parse_name("", b"secret"), (None, None));
+        assert_eq!(
+            parse_name(" lemon ", b"secret"),
+            (Some("lemon".into()), None)
+        );
+        assert_eq!(
+            parse_name("lemon#hunter2", b"secret"),
+            (Some("lemon".into()), Some(trip.clone()))
+        );
+        assert_eq!(parse_name("#hunter2", b"secret"), (None, Some(trip)));
+        assert_eq!(
+            parse_name("lemon#", b"secret"),
+            (Some("lemon".into()), None)
+        );
+    }
+
+    #[test]
+    fn mask_ip_modes() {
+        let masked = |ip: &str, mask| {
+            let mut ip = ip.to_string();
+            mask_ip(&mut ip, mask);
+            ip
+        };
+        assert_eq!(masked("172.20.1.42", IpMask::Show), "172.20.1.42");
+        assert_eq!(masked("172.20.1.42", IpMask::Truncate), "172.20.1.0/24");
+        assert_eq!(
+            masked("fd42:d42:d42:1::5", IpMask::Truncate),
+            "fd42:d42:d42::/48"
+        );
+        assert_eq!(masked("172.20.1.42", IpMask::Hide), "");
+        assert_eq!(masked("garbage", IpMask::Truncate), "");
+    }
+
+    #[test]
+    fn mask_prefix_modes() {
+        let masked = |prefix: &str, mask| {
+            let mut prefix = Some(prefix.to_string());
+            mask_prefix(&mut prefix, mask);
+            prefix
+        };
+        let narrow = "172.20.1.32/28";
+        assert_eq!(masked(narrow, IpMask::Show).as_deref(), Some(narrow));
+        assert_eq!(
+            masked(narrow, IpMask::Truncate).as_deref(),
+            Some("172.20.1.0/24")
+        );
+        assert_eq!(
+            masked("172.20.0.0/14", IpMask::Truncate).as_deref(),
+            Some("172.20.0.0/14")
+        );
+        assert_eq!(
+            masked("fd42:d42:d42:1::/64", IpMask::Truncate).as_deref(),
+            Some("fd42:d42:d42::/48")
+        );
+        assert_eq!(masked(narrow, IpMask::Hide), None);
+        assert_eq!(masked("garbage", IpMask::Truncate), None);
+    }
+
+    #[test]
+    fn masked_posts_render_without_their_network() {
+        let board = || Board {
+            id: 1,
+            name: "b".into(),
+            description: String::new(),
+            color: 0,
+            settings: BoardSettings::default(),
+        };
+        for (mask, shown) in [
+            (IpMask::Truncate, Some("172.20.1.0/24")),
+            (IpMask::Hide, None),
+        ] {
+            let mut posts = vec![Post {
+                id: 1,
+                content: "hello".into(),
+                source: None,
+                image: None,
+                ip: "172.20.1.42".into(),
+                whois: Some(WhoisResult {
+                    asn: 4242420000,
+                    prefix: Some("172.20.1.32/28".into()),
+                    mnt: vec!["LEMON-MNT".into()],
+                    descr: None,
+                }),
+                reply: None,
+                time: NaiveDate::from_ymd_opt(2023, 1, 1)
+                    .unwrap()
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap(),
+                board: 1,
+                thread: None,
+                name: None,
+                tripcode: None,
+                identity: Identity::Full,
+                pending: false,
+            }];
+            assign(&board(), &mut posts, b"secret", mask);
+            let html = BoardView {
+                flash: Default::default(),
+                admin: None,
+                board: board(),
+                year: 2023,
+                month: 1,
+                posts,
+                challenge: None,
+                csrf: String::new(),
+            }
+            .render()
+            .unwrap();
+            assert!(!html.contains("172.20.1.42"));
+            assert!(!html.contains("172.20.1.32"));
+            assert_eq!(html.contains("172.20.1.0/24"), shown.is_some());
+            assert!(html.contains("LEMON-MNT"));
+        }
+    }
+}
@@ -8,3 +8,25 @@ pub fn imghdr(buf: &[u8]) -> Option<&'static str> {
         _ => None,
     }
 }
+
+/// The MIME type for an extension `imghdr` returns.
+pub fn mime(ext: &str) -> &'static str {
+    match ext {
+        ".png" => "image/png",
+        ".jpg" => "image/jpeg",
+        ".gif" => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Whether a name is shaped like the ones uploads are stored under, 32 random
+/// alphanumerics followed by an extension `imghdr` returns.
+pub fn is_image_name(name: &str) -> bool {
+    match (name.get(..32), name.get(32..)) {
+        (Some(stem), Some(ext)) => {
+            stem.bytes().all(|b| b.is_ascii_alphanumeric())
+                && [".png", ".jpg", ".gif"].contains(&ext)
+        }
+        _ => false,
+    }
+}
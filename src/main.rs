@@ -2,12 +2,21 @@
 #![allow(clippy::unreadable_literal)]
 
 use axum::ServiceExt;
-use std::{env, str::FromStr, sync::Arc, thread, time::Duration};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
 use axum_sessions::async_session::{
     base64::{display::Base64Display, URL_SAFE_NO_PAD},
     MemoryStore,
 };
+use chrono::Utc;
 use color_eyre::{
     eyre::{eyre, Context},
     Result,
@@ -19,13 +28,27 @@ use tower_http::normalize_path::NormalizePathLayer;
 use tower_layer::Layer;
 use tracing::Level;
 
-use crate::database::DbExecutor;
+use crate::{
+    database::{DbExecutor, ExecutorConnection},
+    storage::{Filesystem, Images},
+};
 
+mod backup;
+mod challenge;
 mod config;
 mod database;
+mod export;
+mod filter;
+mod fsck;
+mod identity;
 mod imghdr;
+mod markup;
+mod moderation;
 mod router;
+mod storage;
+mod tar;
 mod templates;
+mod totp;
 mod whois;
 
 #[cfg(unix)]
@@ -61,6 +84,34 @@ async fn main() -> Result<()> {
                 let b64 = Base64Display::with_config(&bytes, URL_SAFE_NO_PAD);
                 println!("{b64}");
             }
+            "reset-2fa" => match env::args().nth(2) {
+                Some(admin) => reset_2fa(admin).await?,
+                None => eprintln!("Usage: zhaba reset-2fa <admin>"),
+            },
+            "backup" => match env::args().nth(2) {
+                Some(out) => backup(out.into()).await?,
+                None => eprintln!("Usage: zhaba backup <out.tar>"),
+            },
+            "restore" => match env::args().skip(2).collect::<Vec<_>>().as_slice() {
+                [archive] => restore(archive.into(), false).await?,
+                [archive, force] if force == "--force" => restore(archive.into(), true).await?,
+                _ => eprintln!("Usage: zhaba restore <backup.tar> [--force]"),
+            },
+            "export" => match env::args().skip(2).collect::<Vec<_>>().as_slice() {
+                [board, out] => export_board(board.clone(), out.into()).await?,
+                _ => eprintln!("Usage: zhaba export <board> <out.tar>"),
+            },
+            "import" => match env::args().skip(2).collect::<Vec<_>>().as_slice() {
+                [archive] => import_board(archive.into(), None).await?,
+                [archive, name] => import_board(archive.into(), Some(name.clone())).await?,
+                _ => eprintln!("Usage: zhaba import <export.tar> [board name]"),
+            },
+            "fsck" => match env::args().nth(2).as_deref() {
+                None => fsck(false).await?,
+                Some("--fix") => fsck(true).await?,
+                Some(_) => eprintln!("Usage: zhaba fsck [--fix]"),
+            },
+            "migrate-images" => migrate_images()?,
             _ => {
                 eprintln!("Error: Invalid subcommand '{subcommand}'");
             }
@@ -76,21 +127,35 @@ async fn main() -> Result<()> {
 
     tracing::info!(concat!("Initializing - zhaba v", env!("CARGO_PKG_VERSION")));
 
-    if !cfg.image_path.is_dir() {
-        return Err(eyre!(
-            "The image path {:?} is not a directory",
-            cfg.image_path
-        ));
-    }
+    let images = Images::new(&cfg)?;
 
-    let (db_exec, db_conn) = DbExecutor::create(cfg.db.as_deref().unwrap_or("zhaba.db3"))?;
+    let (db_exec, db_conn) =
+        DbExecutor::create(cfg.db.as_deref().unwrap_or("zhaba.db3"), &cfg.database)?;
     let exec_thread = thread::spawn(move || db_exec.run());
 
     let session_store = MemoryStore::new();
     let (ctx, _) = broadcast::channel(1);
     let maintenance_task = tokio::spawn(maintenance(ctx.subscribe(), session_store.clone(), 3600));
+    let fsck_task = (cfg.fsck.interval_secs != 0).then(|| {
+        let job = fsck_job(
+            ctx.subscribe(),
+            db_conn.clone(),
+            images.clone(),
+            cfg.clone(),
+        );
+        tokio::spawn(job)
+    });
+    let backup_task = (cfg.backup.interval_secs != 0).then(|| {
+        let job = backup_job(
+            ctx.subscribe(),
+            db_conn.clone(),
+            images.clone(),
+            cfg.clone(),
+        );
+        tokio::spawn(job)
+    });
 
-    let router = router::build(db_conn, cfg.clone(), session_store)?;
+    let router = router::build(db_conn, cfg.clone(), images, session_store)?;
     let normalized_router = NormalizePathLayer::trim_trailing_slash().layer(router);
 
     tracing::info!("Listening on http://{}", cfg.listen);
@@ -105,12 +170,210 @@ async fn main() -> Result<()> {
     tracing::info!("Waiting for the maintenance task to shut down");
     let _ = ctx.send(());
     maintenance_task.await.unwrap();
+    for task in [fsck_task, backup_task].into_iter().flatten() {
+        task.await.unwrap();
+    }
     tracing::info!("Waiting for the database to shut down");
-    exec_thread.join().unwrap();
+    join_executor(exec_thread).await;
     tracing::info!("Shutdown complete!");
     Ok(())
 }
 
+/// Starts the executor for a subcommand, without readers since it's the only user.
+/// The thread finishes once the connection is dropped.
+fn open_database(cfg: &Config) -> Result<(ExecutorConnection, JoinHandle<()>)> {
+    let options = config::Database {
+        readers: 0,
+        ..cfg.database
+    };
+    let (db_exec, db_conn) =
+        DbExecutor::create(cfg.db.as_deref().unwrap_or("zhaba.db3"), &options)?;
+    Ok((db_conn, thread::spawn(move || db_exec.run())))
+}
+
+/// Waits for the executor thread to finish, off the runtime's own thread.
+async fn join_executor(thread: JoinHandle<()>) {
+    tokio::task::spawn_blocking(move || thread.join().unwrap())
+        .await
+        .unwrap();
+}
+
+/// Removes an admin's TOTP secret and recovery codes, for when they've lost both.
+async fn reset_2fa(admin: String) -> Result<()> {
+    let cfg = Config::load().wrap_err("Failed to load the configuration file")?;
+    let (db_conn, exec_thread) = open_database(&cfg)?;
+    let removed = db_conn.reset_totp(admin.clone()).await?;
+    drop(db_conn);
+    join_executor(exec_thread).await;
+    if removed {
+        println!("Two-factor authentication reset for {admin:?}");
+    } else {
+        println!("{admin:?} doesn't have two-factor authentication set up");
+    }
+    Ok(())
+}
+
+/// Backs up the database and images while the server may be running.
+async fn backup(out: PathBuf) -> Result<()> {
+    let cfg = Config::load().wrap_err("Failed to load the configuration file")?;
+    let images = Images::new(&cfg)?;
+    let (db_conn, exec_thread) = open_database(&cfg)?;
+    let result = backup::create(&db_conn, &images, &out).await;
+    drop(db_conn);
+    join_executor(exec_thread).await;
+    let images = result?;
+    println!(
+        "Backed up the database and {images} images to {}",
+        out.display()
+    );
+    Ok(())
+}
+
+/// Replaces the database with a backup, which needs the server to be stopped.
+async fn restore(archive: PathBuf, force: bool) -> Result<()> {
+    let cfg = Config::load().wrap_err("Failed to load the configuration file")?;
+    let db_path = Path::new(cfg.db.as_deref().unwrap_or("zhaba.db3"));
+    if db_path.exists() && !force {
+        return Err(eyre!(
+            "{} already exists, stop the server and pass --force to replace it",
+            db_path.display()
+        ));
+    }
+    let images = Images::new(&cfg)?;
+    let restored = backup::restore(&archive, db_path, &images).await?;
+    println!("Restored the database and {} images", restored.images);
+    if restored.missing != 0 {
+        println!(
+            "{} images were already missing when the backup was made, run `zhaba fsck --fix` to clear them",
+            restored.missing
+        );
+    }
+    Ok(())
+}
+
+/// Writes a board, its posts and their images to a tarball that `import` reads.
+async fn export_board(board: String, out: PathBuf) -> Result<()> {
+    let cfg = Config::load().wrap_err("Failed to load the configuration file")?;
+    let images = Images::new(&cfg)?;
+    let (db_conn, exec_thread) = open_database(&cfg)?;
+    let result = export::export(&db_conn, &images, board.clone(), &out).await;
+    drop(db_conn);
+    join_executor(exec_thread).await;
+    match result? {
+        Some(posts) => println!("Exported /{board}/ with {posts} posts to {}", out.display()),
+        None => return Err(eyre!("There's no board named {board:?}")),
+    }
+    Ok(())
+}
+
+async fn import_board(archive: PathBuf, name: Option<String>) -> Result<()> {
+    let cfg = Config::load().wrap_err("Failed to load the configuration file")?;
+    let images = Images::new(&cfg)?;
+    let (db_conn, exec_thread) = open_database(&cfg)?;
+    let result = export::import(&db_conn, &images, &archive, name).await;
+    drop(db_conn);
+    join_executor(exec_thread).await;
+    println!("{}", result?);
+    Ok(())
+}
+
+/// Reports, or with `--fix` repairs, images and posts that don't match up.
+async fn fsck(fix: bool) -> Result<()> {
+    let cfg = Config::load().wrap_err("Failed to load the configuration file")?;
+    let images = Images::new(&cfg)?;
+    let (db_conn, exec_thread) = open_database(&cfg)?;
+    let report = fsck::check(&db_conn, &images).await?;
+    println!("{report}");
+    if fix && !report.is_clean() {
+        fsck::repair(&db_conn, &images, &report).await?;
+        println!("Repaired");
+    }
+    drop(db_conn);
+    join_executor(exec_thread).await;
+    if !fix && !report.is_clean() {
+        eprintln!("Run `zhaba fsck --fix` to repair them");
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Moves images stored before the image directory was sharded into their shards.
+fn migrate_images() -> Result<()> {
+    let cfg = Config::load().wrap_err("Failed to load the configuration file")?;
+    if !matches!(cfg.storage, config::Storage::Filesystem) {
+        return Err(eyre!("Only images stored on the filesystem are sharded"));
+    }
+    let moved = Filesystem::new(cfg.image_path.clone()).migrate()?;
+    println!("Moved {moved} images into the sharded layout");
+    Ok(())
+}
+
+async fn fsck_job(
+    mut shutdown: broadcast::Receiver<()>,
+    db: ExecutorConnection,
+    images: Images,
+    cfg: Arc<Config>,
+) {
+    let interval = Duration::from_secs(cfg.fsck.interval_secs);
+    loop {
+        select! {
+            _ = sleep(interval) => {}
+            _ = shutdown.recv() => return,
+        }
+        let report = match fsck::check(&db, &images).await {
+            Ok(report) => report,
+            Err(e) => {
+                tracing::error!("Failed to check images: {e}");
+                continue;
+            }
+        };
+        if report.is_clean() {
+            tracing::debug!("Images are consistent");
+            continue;
+        }
+        tracing::warn!("Image check: {report}");
+        if cfg.fsck.repair {
+            if let Err(e) = fsck::repair(&db, &images, &report).await {
+                tracing::error!("Failed to repair images: {e}");
+            }
+        }
+    }
+}
+
+async fn backup_job(
+    mut shutdown: broadcast::Receiver<()>,
+    db: ExecutorConnection,
+    images: Images,
+    cfg: Arc<Config>,
+) {
+    let interval = Duration::from_secs(cfg.backup.interval_secs);
+    let directory = &cfg.backup.directory;
+    loop {
+        select! {
+            _ = sleep(interval) => {}
+            _ = shutdown.recv() => return,
+        }
+        if let Err(e) = std::fs::create_dir_all(directory) {
+            tracing::error!("Failed to create the backup directory: {e}");
+            continue;
+        }
+        let out = directory.join(format!("zhaba-{}.tar", Utc::now().format("%Y%m%d-%H%M%S")));
+        match backup::create(&db, &images, &out).await {
+            Ok(images) => tracing::info!(
+                "Backed up the database and {images} images to {}",
+                out.display()
+            ),
+            Err(e) => {
+                tracing::error!("Failed to back up: {e}");
+                continue;
+            }
+        }
+        if let Err(e) = backup::prune(directory, cfg.backup.keep) {
+            tracing::error!("Failed to remove old backups: {e}");
+        }
+    }
+}
+
 async fn maintenance(
     mut shutdown: broadcast::Receiver<()>,
     session_store: MemoryStore,
@@ -0,0 +1,746 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use ammonia::{Builder, UrlRelative};
+use bbscope::{BBCode, EmitSimple, MatchInfo, MatchType, ScopeInfo};
+use chrono::Datelike;
+use html_escape::encode_quoted_attribute;
+use lru::LruCache;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+use crate::templates::models::{Post, ReplyTo};
+
+// matcher ids, used in `ScopeInfo::only`
+const TEXT: &str = "text";
+const CONSUME: &str = "consume";
+const MARKER: &str = "marker";
+const GT: &str = "gt";
+const POST_REF: &str = "postref";
+const GREENTEXT: &str = "greentext";
+const NEWLINE: &str = "newline";
+const AUTOLINK: &str = "autolink";
+
+const RENDER_CACHE_CAPACITY: usize = 4096;
+
+// inserted around greentext lines before parsing, stripped from user input
+const GREENTEXT_OPEN: char = '\x01';
+const GREENTEXT_CLOSE: char = '\x02';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    B,
+    I,
+    U,
+    S,
+    Sup,
+    Sub,
+    Quote,
+    Spoiler,
+    Code,
+    Url,
+}
+
+impl Tag {
+    pub const ALL: [Self; 10] = [
+        Self::B,
+        Self::I,
+        Self::U,
+        Self::S,
+        Self::Sup,
+        Self::Sub,
+        Self::Quote,
+        Self::Spoiler,
+        Self::Code,
+        Self::Url,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::B => "b",
+            Self::I => "i",
+            Self::U => "u",
+            Self::S => "s",
+            Self::Sup => "sup",
+            Self::Sub => "sub",
+            Self::Quote => "quote",
+            Self::Spoiler => "spoiler",
+            Self::Code => "code",
+            Self::Url => "url",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.name() == name)
+    }
+
+    /// Sample markup shown on the about page.
+    pub fn example(self) -> &'static str {
+        match self {
+            Self::B => "[b]bold[/b]",
+            Self::I => "[i]italic[/i]",
+            Self::U => "[u]underline[/u]",
+            Self::S => "[s]strikethrough[/s]",
+            Self::Sup => "x[sup]superscript[/sup]",
+            Self::Sub => "x[sub]subscript[/sub]",
+            Self::Quote => "[quote=someone]a quote[/quote]",
+            Self::Spoiler => "[spoiler]hidden text[/spoiler]",
+            Self::Code => "[code]fn main() {}[/code]",
+            Self::Url => "[url=https://dn42.dev]a link[/url]",
+        }
+    }
+
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// A set of accepted BBCode tags. (De)serializes as a list of tag names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct TagSet(u16);
+
+impl TagSet {
+    pub const ALL: Self = Self((1 << Tag::ALL.len()) - 1);
+
+    pub fn contains(self, tag: Tag) -> bool {
+        self.0 & tag.bit() != 0
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Tag> {
+        Tag::ALL.into_iter().filter(move |t| self.contains(*t))
+    }
+
+    /// Parses a whitespace or comma separated list of tag names.
+    pub fn parse(list: &str) -> Result<Self, String> {
+        list.split([' ', ','])
+            .filter(|n| !n.is_empty())
+            .try_fold(Self(0), |set, name| match Tag::from_name(name) {
+                Some(tag) => Ok(Self(set.0 | tag.bit())),
+                None => Err(format!("Unknown BBCode tag '{name}'")),
+            })
+    }
+}
+
+impl Default for TagSet {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl TryFrom<Vec<String>> for TagSet {
+    type Error = String;
+
+    fn try_from(names: Vec<String>) -> Result<Self, Self::Error> {
+        Self::parse(&names.join(" "))
+    }
+}
+
+impl From<TagSet> for Vec<String> {
+    fn from(tags: TagSet) -> Self {
+        tags.iter().map(|t| t.name().to_string()).collect()
+    }
+}
+
+impl fmt::Display for TagSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.iter().map(Tag::name).collect();
+        f.write_str(&names.join(" "))
+    }
+}
+
+pub struct Example {
+    pub source: &'static str,
+    pub html: String,
+}
+
+/// Renders post markup into HTML. Post references are emitted as `#id` links,
+/// use [`link_references`] to point them at the right board and month.
+///
+/// The output is passed through [`sanitize`], so even a bug in a matcher can't
+/// produce markup the enabled tags wouldn't.
+pub fn render(tags: TagSet, source: &str) -> String {
+    sanitize(tags, &renderer(tags).bbcode.parse(&mark_greentext(source)))
+}
+
+/// Strips every element, attribute and class that `tags` can't produce.
+pub fn sanitize(tags: TagSet, html: &str) -> String {
+    renderer(tags).sanitizer.clean(html).to_string()
+}
+
+/// What a post is rendered from, markup or legacy HTML.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum RenderInput {
+    Source(String),
+    Html(String),
+}
+
+/// Renders by tag set and input, dropping the least recently used once full.
+type RenderCache = LruCache<(TagSet, RenderInput), String>;
+
+fn render_cache() -> RenderCache {
+    LruCache::new(NonZeroUsize::new(RENDER_CACHE_CAPACITY).unwrap())
+}
+
+/// Fills in `Post::content` from its source. Renders are cached by tag set and
+/// source, so re-rendering only happens for new posts or changed settings.
+/// Legacy posts stored as HTML are sanitized with every tag allowed.
+pub fn render_post(tags: TagSet, post: &mut Post) {
+    static CACHE: OnceLock<Mutex<RenderCache>> = OnceLock::new();
+    let input = match &post.source {
+        Some(source) => RenderInput::Source(source.clone()),
+        None => RenderInput::Html(post.content.clone()),
+    };
+    let key = (tags, input);
+
+    let cache = CACHE.get_or_init(|| Mutex::new(render_cache()));
+    if let Some(html) = cache.lock().unwrap().get(&key) {
+        post.content = html.to_owned();
+        return;
+    }
+    let html = match &key.1 {
+        RenderInput::Source(source) => render(tags, source),
+        RenderInput::Html(html) => sanitize(TagSet::ALL, html),
+    };
+    cache.lock().unwrap().put(key, html.clone());
+    post.content = html;
+}
+
+/// Ids of all the posts referenced in rendered HTML.
+pub fn references(html: &str) -> Vec<u64> {
+    let mut ids: Vec<u64> = post_ref_regex()
+        .captures_iter(html)
+        .filter_map(|c| c[1].parse().ok())
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+/// Rewrites post references to point at the posts they resolved to. References
+/// to posts that couldn't be found are left without a link target.
+pub fn link_references(html: &str, resolved: &[ReplyTo]) -> String {
+    post_ref_regex()
+        .replace_all(html, |c: &Captures| {
+            let target = c[1]
+                .parse::<u64>()
+                .ok()
+                .and_then(|id| resolved.iter().find(|r| r.id == id));
+            match target {
+                Some(post) => format!(
+                    r#"<a class="post-ref" href="/{}?y={}&amp;m={}#{}">"#,
+                    encode_quoted_attribute(&post.board_name),
+                    post.time.year(),
+                    post.time.month(),
+                    post.id
+                ),
+                None => r#"<a class="post-ref dead-ref">"#.into(),
+            }
+        })
+        .into_owned()
+}
+
+/// Every enabled tag and the always-on markup, rendered for the about page.
+pub fn examples(tags: TagSet) -> Vec<Example> {
+    tags.iter()
+        .map(Tag::example)
+        .chain([">greentext", ">>1 (post reference)"])
+        .map(|source| Example {
+            source,
+            html: render(tags, source),
+        })
+        .collect()
+}
+
+fn post_ref_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(r##"<a class="post-ref" href="#([0-9]+)">"##).unwrap())
+}
+
+struct Renderer {
+    bbcode: BBCode,
+    sanitizer: Builder<'static>,
+}
+
+fn renderer(tags: TagSet) -> Arc<Renderer> {
+    static RENDERERS: OnceLock<Mutex<HashMap<TagSet, Arc<Renderer>>>> = OnceLock::new();
+    RENDERERS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(tags)
+        .or_insert_with(|| {
+            Arc::new(Renderer {
+                bbcode: build_parser(tags).unwrap(),
+                sanitizer: build_sanitizer(tags),
+            })
+        })
+        .clone()
+}
+
+/// Wraps lines starting with `>` (but not `>>123` references) in greentext markers.
+fn mark_greentext(source: &str) -> String {
+    let source = source.replace([GREENTEXT_OPEN, GREENTEXT_CLOSE], "");
+    let mut marked = String::with_capacity(source.len());
+    for line in source.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\r', '\n']);
+        let is_reference = content
+            .strip_prefix(">>")
+            .is_some_and(|r| r.starts_with(|c: char| c.is_ascii_digit()));
+        if content.starts_with('>') && !is_reference {
+            marked.push(GREENTEXT_OPEN);
+            marked.push_str(content);
+            marked.push(GREENTEXT_CLOSE);
+            marked.push_str(&line[content.len()..]);
+        } else {
+            marked.push_str(line);
+        }
+    }
+    marked
+}
+
+fn is_safe_url(url: &str) -> bool {
+    let url = url.trim_start().to_ascii_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+fn link(href: &str, body: &str) -> String {
+    format!(r#"<a href="{href}" rel="nofollow noopener" target="_blank">{body}</a>"#)
+}
+
+fn simple(id: &'static str, regex: &str, emit: EmitSimple) -> Result<MatchInfo, regex::Error> {
+    Ok(MatchInfo {
+        id,
+        regex: Regex::new(regex)?,
+        match_type: MatchType::Simple(emit),
+    })
+}
+
+fn build_parser(tags: TagSet) -> Result<BBCode, regex::Error> {
+    let plaintext = || Some(vec![TEXT, CONSUME, MARKER, GT]);
+    let mut matchers = vec![
+        simple(
+            TEXT,
+            r"^[^\[\n\rh>\x01\x02]+",
+            Arc::new(|c| encode_quoted_attribute(&c[0]).into_owned()),
+        )?,
+        simple(CONSUME, r"^\r+", Arc::new(|_| String::new()))?,
+        simple(
+            POST_REF,
+            r"^>>([0-9]{1,18})",
+            Arc::new(|c| {
+                format!(
+                    r##"<a class="post-ref" href="#{0}">&gt;&gt;{0}</a>"##,
+                    &c[1]
+                )
+            }),
+        )?,
+        MatchInfo {
+            id: GREENTEXT,
+            regex: Regex::new(r"^\x01")?,
+            match_type: MatchType::Open(Arc::new(ScopeInfo::basic(Arc::new(|_, b, _| {
+                format!(r#"<span class="greentext">{b}</span>"#)
+            })))),
+        },
+        MatchInfo {
+            id: GREENTEXT,
+            regex: Regex::new(r"^\x02")?,
+            match_type: MatchType::Close,
+        },
+        simple(MARKER, r"^[\x01\x02]", Arc::new(|_| String::new()))?,
+        simple(GT, r"^>", Arc::new(|_| "&gt;".into()))?,
+    ];
+
+    for tag in tags.iter() {
+        let name = tag.name();
+        let (info, open_consume, close_consume) = match tag {
+            Tag::B | Tag::I | Tag::U | Tag::S | Tag::Sup | Tag::Sub => (
+                ScopeInfo::basic(Arc::new(move |_, b, _| format!("<{name}>{b}</{name}>"))),
+                None,
+                None,
+            ),
+            Tag::Quote => (
+                ScopeInfo::basic(Arc::new(|o, b, _| {
+                    match o.as_ref().and_then(|o| o.name("attr")) {
+                        Some(cite) => format!(
+                            "<blockquote><cite>{}</cite>{b}</blockquote>",
+                            encode_quoted_attribute(cite.as_str())
+                        ),
+                        None => format!("<blockquote>{b}</blockquote>"),
+                    }
+                })),
+                Some((0, 1)),
+                Some((0, 1)),
+            ),
+            Tag::Spoiler => (
+                ScopeInfo::basic(Arc::new(|_, b, _| {
+                    format!(r#"<span class="spoiler">{b}</span>"#)
+                })),
+                None,
+                None,
+            ),
+            Tag::Code => (
+                ScopeInfo {
+                    only: plaintext(),
+                    double_closes: false,
+                    emit: Arc::new(|_, b, _| format!(r#"<pre class="code">{b}</pre>"#)),
+                },
+                Some((0, 1)),
+                Some((0, 1)),
+            ),
+            Tag::Url => (
+                ScopeInfo {
+                    only: plaintext(),
+                    double_closes: false,
+                    emit: Arc::new(|o, b, _| {
+                        let href = match o.as_ref().and_then(|o| o.name("attr")) {
+                            Some(attr) => encode_quoted_attribute(attr.as_str().trim()),
+                            None => b.trim().into(),
+                        };
+                        if is_safe_url(&href) {
+                            link(&href, b)
+                        } else {
+                            b.to_string()
+                        }
+                    }),
+                },
+                None,
+                None,
+            ),
+        };
+        BBCode::add_tagmatcher(&mut matchers, name, info, open_consume, close_consume)?;
+    }
+
+    matchers.push(simple(NEWLINE, r"^\n", Arc::new(|_| "<br>".into()))?);
+
+    if tags.contains(Tag::Url) {
+        let url_chars = "[-a-zA-Z0-9_/%&=#+~@$*'!?,.;:]*";
+        let end_chars = "[-a-zA-Z0-9_/%&=#+~@$*']";
+        matchers.push(simple(
+            AUTOLINK,
+            &format!("^https?://{url_chars}{end_chars}"),
+            Arc::new(|c| {
+                let url = encode_quoted_attribute(&c[0]);
+                link(&url, &url)
+            }),
+        )?);
+    }
+
+    Ok(BBCode::from_matchers(matchers))
+}
+
+/// Relative URLs are only kept as `#id` post references. Anything else could
+/// lead off the site, like the scheme-relative `//example.com`.
+fn same_page_only(url: &str) -> Option<Cow<'_, str>> {
+    url.starts_with('#').then_some(Cow::Borrowed(url))
+}
+
+/// The allowlist mirroring what `build_parser` emits for the same tags.
+fn build_sanitizer(tags: TagSet) -> Builder<'static> {
+    let mut elements = HashSet::from(["br", "span", "a"]);
+    let mut classes = HashMap::from([
+        ("span", HashSet::from(["greentext"])),
+        ("a", HashSet::from(["post-ref", "dead-ref"])),
+    ]);
+    let mut a_attributes = HashSet::from(["href"]);
+    for tag in tags.iter() {
+        match tag {
+            Tag::B | Tag::I | Tag::U | Tag::S | Tag::Sup | Tag::Sub => {
+                elements.insert(tag.name());
+            }
+            Tag::Quote => elements.extend(["blockquote", "cite"]),
+            Tag::Spoiler => {
+                classes.get_mut("span").unwrap().insert("spoiler");
+            }
+            Tag::Code => {
+                elements.insert("pre");
+                classes.insert("pre", HashSet::from(["code"]));
+            }
+            Tag::Url => a_attributes.extend(["rel", "target"]),
+        }
+    }
+
+    let mut builder = Builder::empty();
+    builder
+        .tags(elements)
+        .allowed_classes(classes)
+        .tag_attributes(HashMap::from([("a", a_attributes)]))
+        .url_schemes(HashSet::from(["http", "https"]))
+        .url_relative(UrlRelative::Custom(Box::new(same_page_only)))
+        .link_rel(None)
+        .strip_comments(true);
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn render_cache_drops_the_least_recently_used() {
+        let key = |n: usize| (TagSet::ALL, RenderInput::Source(n.to_string()));
+        let mut cache = render_cache();
+        for n in 0..RENDER_CACHE_CAPACITY {
+            cache.put(key(n), format!("<p>{n}</p>"));
+        }
+        assert_eq!(cache.get(&key(0)).unwrap(), "<p>0</p>");
+        cache.put(key(RENDER_CACHE_CAPACITY), String::new());
+        assert_eq!(cache.len(), RENDER_CACHE_CAPACITY);
+        assert_eq!(cache.get(&key(0)).unwrap(), "<p>0</p>");
+        assert_eq!(cache.get(&key(1)), None);
+        // the same source is cached apart from legacy HTML and other tag sets
+        let html = (TagSet::ALL, RenderInput::Html("2".into()));
+        assert_eq!(cache.get(&html), None);
+        assert_eq!(
+            cache.get(&(TagSet(0), RenderInput::Source("2".into()))),
+            None
+        );
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            render(TagSet::ALL, "<script>alert('x')</script>"),
+            "&lt;script&gt;alert('x')&lt;/script&gt;"
+        );
+    }
+
+    #[test]
+    fn basic_tags_and_disabled_tags() {
+        assert_eq!(render(TagSet::ALL, "[b]hi[/b]"), "<b>hi</b>");
+        let only_i = TagSet::parse("i").unwrap();
+        assert_eq!(render(only_i, "[b]hi[/b] [i]x[/i]"), "[b]hi[/b] <i>x</i>");
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            render(TagSet::ALL, "[url=https://dn42.dev]dn42[/url]"),
+            r#"<a href="https://dn42.dev" rel="nofollow noopener" target="_blank">dn42</a>"#
+        );
+        assert_eq!(render(TagSet::ALL, "[url=javascript:alert(1)]x[/url]"), "x");
+        assert_eq!(
+            render(TagSet::ALL, "[url=\"onmouseover=alert(1)]x[/url]"),
+            "x"
+        );
+        assert_eq!(
+            render(TagSet::ALL, "see https://dn42.dev"),
+            r#"see <a href="https://dn42.dev" rel="nofollow noopener" target="_blank">https://dn42.dev</a>"#
+        );
+        assert_eq!(
+            render(TagSet::ALL, "[url] https://dn42.dev[/url]"),
+            r#"<a href="https://dn42.dev" rel="nofollow noopener" target="_blank"> https://dn42.dev</a>"#
+        );
+    }
+
+    #[test]
+    fn code_is_verbatim() {
+        assert_eq!(
+            render(TagSet::ALL, "[code][b]x[/b]\n>>1 <a>[/code]"),
+            r#"<pre class="code">[b]x[/b]
+&gt;&gt;1 &lt;a&gt;</pre>"#
+        );
+    }
+
+    #[test]
+    fn greentext_and_references() {
+        assert_eq!(
+            render(TagSet::ALL, ">be me\n>>12 lol\na > b"),
+            concat!(
+                r#"<span class="greentext">&gt;be me</span><br>"#,
+                r##"<a class="post-ref" href="#12">&gt;&gt;12</a> lol<br>a &gt; b"##
+            )
+        );
+        assert_eq!(render(TagSet::ALL, "\x01[b]x\x02"), "<b>x</b>");
+    }
+
+    #[test]
+    fn reference_linking() {
+        let html = render(TagSet::ALL, ">>3 >>5 >>3");
+        assert_eq!(references(&html), vec![3, 5]);
+        let resolved = [ReplyTo {
+            id: 3,
+            time: NaiveDate::from_ymd_opt(2023, 9, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            board: 1,
+            board_name: "b".into(),
+        }];
+        assert_eq!(
+            link_references(&html, &resolved),
+            concat!(
+                r##"<a class="post-ref" href="/b?y=2023&amp;m=9#3">&gt;&gt;3</a> "##,
+                r#"<a class="post-ref dead-ref">&gt;&gt;5</a> "#,
+                r##"<a class="post-ref" href="/b?y=2023&amp;m=9#3">&gt;&gt;3</a>"##
+            )
+        );
+    }
+
+    #[test]
+    fn tag_set_parsing() {
+        let set = TagSet::parse("b, quote url").unwrap();
+        assert_eq!(set.to_string(), "b quote url");
+        assert!(TagSet::parse("b blink").is_err());
+        assert_eq!(TagSet::parse(&TagSet::ALL.to_string()), Ok(TagSet::ALL));
+    }
+
+    #[test]
+    fn sanitizes_legacy_html() {
+        assert_eq!(
+            sanitize(
+                TagSet::ALL,
+                r#"<img src=x onerror=alert(1)><b onclick="x">a</b><a href="javascript:x" class="post-ref evil">y</a><script>z</script>"#
+            ),
+            r#"<b>a</b><a class="post-ref">y</a>"#
+        );
+        let only_b = TagSet::parse("b").unwrap();
+        assert_eq!(
+            sanitize(
+                only_b,
+                r#"<i>a</i><span class="spoiler">b</span><a href="https://x" target="_blank">c</a>"#
+            ),
+            r#"a<span class="">b</span><a href="https://x">c</a>"#
+        );
+        // only post references may be relative
+        assert_eq!(
+            sanitize(
+                TagSet::ALL,
+                r##"<a href="//evil.example">a</a><a href="/b">b</a><a class="post-ref" href="#3">c</a>"##
+            ),
+            r##"<a>a</a><a>b</a><a class="post-ref" href="#3">c</a>"##
+        );
+    }
+
+    const ALLOWED_ATTRIBUTES: [&str; 4] = ["class", "href", "rel", "target"];
+
+    /// Checks that every element and attribute in `html` is one the renderer can emit.
+    fn assert_no_injection(html: &str) -> Result<(), TestCaseError> {
+        let tag = Regex::new(r"<\s*/?\s*([^\s/>]*)([^>]*)>").unwrap();
+        let attribute = Regex::new(r#"([^\s=]+)(?:\s*=\s*"([^"]*)")?"#).unwrap();
+        let allowed = [
+            "a",
+            "b",
+            "i",
+            "u",
+            "s",
+            "sup",
+            "sub",
+            "blockquote",
+            "cite",
+            "span",
+            "pre",
+            "br",
+        ];
+        for element in tag.captures_iter(html) {
+            prop_assert!(
+                allowed.contains(&&element[1]),
+                "element {:?} in {html:?}",
+                &element[1]
+            );
+            for attr in attribute.captures_iter(&element[2]) {
+                prop_assert!(
+                    ALLOWED_ATTRIBUTES.contains(&&attr[1]),
+                    "attribute {:?} in {html:?}",
+                    &attr[1]
+                );
+                let value = attr.get(2).map_or("", |v| v.as_str());
+                if &attr[1] == "href" {
+                    prop_assert!(
+                        ["http://", "https://", "#"]
+                            .iter()
+                            .any(|p| value.starts_with(p)),
+                        "href {value:?} in {html:?}"
+                    );
+                }
+            }
+        }
+        let lower = html.to_ascii_lowercase();
+        prop_assert!(!lower.contains("<script"), "script in {html:?}");
+        Ok(())
+    }
+
+    fn adversarial_fragment() -> impl Strategy<Value = &'static str> {
+        prop::sample::select(vec![
+            "[b]",
+            "[/b]",
+            "[i]",
+            "[/i]",
+            "[s]",
+            "[/s]",
+            "[sup]",
+            "[/sup]",
+            "[quote]",
+            "[quote=",
+            "[/quote]",
+            "[spoiler]",
+            "[/spoiler]",
+            "[code]",
+            "[/code]",
+            "[url]",
+            "[url=",
+            "[/url]",
+            "]",
+            "[",
+            "\"",
+            "'",
+            "=",
+            " ",
+            "\n",
+            "\r",
+            ">",
+            ">>",
+            ">>1",
+            "<",
+            "&",
+            "&#",
+            "\x01",
+            "\x02",
+            "<script>",
+            "</script>",
+            "<img src=x onerror=",
+            "onmouseover=",
+            "javascript:",
+            "JaVaScRiPt:",
+            "data:text/html,",
+            "https://",
+            "http://",
+            "dn42.dev",
+            "alert(1)",
+            "\" onclick=\"",
+            "' onfocus='",
+            "<a href=",
+            "/>",
+            "`",
+        ])
+    }
+
+    proptest! {
+        #[test]
+        fn adversarial_bbcode_is_sanitized(
+            fragments in prop::collection::vec(adversarial_fragment(), 0..40),
+            bits in 0u16..=TagSet::ALL.0,
+        ) {
+            let tags = TagSet(bits);
+            let source = fragments.concat();
+            let raw = renderer(tags).bbcode.parse(&mark_greentext(&source));
+            assert_no_injection(&raw)?;
+            let html = render(tags, &source);
+            assert_no_injection(&html)?;
+            prop_assert_eq!(sanitize(tags, &html), html);
+        }
+
+        #[test]
+        fn arbitrary_input_is_sanitized(source in any::<String>(), bits in 0u16..=TagSet::ALL.0) {
+            assert_no_injection(&render(TagSet(bits), &source))?;
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::{fmt, net::IpAddr};
+
+/// An entry of the untrusted list, whose posts wait for approval on every board.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Untrusted {
+    Asn(u32),
+    /// A single address is stored as a network with the full prefix length.
+    Network(IpAddr, u8),
+}
+
+impl Untrusted {
+    /// Parses `AS4242420000`, `172.20.0.1` or `fd00::/8`.
+    pub fn parse(entry: &str) -> Result<Self, String> {
+        let entry = entry.trim();
+        if let Some(asn) = entry
+            .strip_prefix("AS")
+            .or_else(|| entry.strip_prefix("as"))
+        {
+            return asn
+                .parse()
+                .map(Self::Asn)
+                .map_err(|_| format!("invalid ASN {entry:?}"));
+        }
+        let (addr, prefix) = match entry.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (entry, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address {addr:?}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length {prefix:?}"))?,
+            None => max,
+        };
+        Ok(Self::Network(network(addr, prefix), prefix))
+    }
+
+    /// Whether a poster from `ip`, announced by `asn` if whois knew it, is covered.
+    pub fn matches(&self, ip: IpAddr, asn: Option<u32>) -> bool {
+        match *self {
+            Self::Asn(untrusted) => asn == Some(untrusted),
+            Self::Network(net, prefix) => {
+                net.is_ipv4() == ip.is_ipv4() && network(ip, prefix) == net
+            }
+        }
+    }
+}
+
+impl fmt::Display for Untrusted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Asn(asn) => write!(f, "AS{asn}"),
+            Self::Network(addr, prefix) if u32::from(*prefix) == max_prefix(*addr) => {
+                write!(f, "{addr}")
+            }
+            Self::Network(addr, prefix) => write!(f, "{addr}/{prefix}"),
+        }
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u32 {
+    if addr.is_ipv4() {
+        32
+    } else {
+        128
+    }
+}
+
+/// Clears the host bits of `addr`.
+fn network(addr: IpAddr, prefix: u8) -> IpAddr {
+    let host_bits = max_prefix(addr) - u32::from(prefix);
+    match addr {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(host_bits).unwrap_or(0);
+            IpAddr::V4((u32::from(v4) & mask).into())
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+            IpAddr::V6((u128::from(v6) & mask).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        let cases = [
+            ("AS4242420000", "AS4242420000"),
+            (" as64512 ", "AS64512"),
+            ("172.20.1.2", "172.20.1.2"),
+            ("172.20.1.2/16", "172.20.0.0/16"),
+            ("fd42:1234::1/32", "fd42:1234::/32"),
+            ("0.0.0.0/0", "0.0.0.0/0"),
+        ];
+        for (input, shown) in cases {
+            assert_eq!(Untrusted::parse(input).unwrap().to_string(), shown);
+        }
+        for invalid in [
+            "ASx",
+            "172.20.1",
+            "172.20.0.0/33",
+            "fd00::/129",
+            "",
+            "10.0.0.0/",
+        ] {
+            assert!(Untrusted::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn matching() {
+        let ip = "172.20.1.2".parse().unwrap();
+        let v6 = "fd42:1234::1".parse().unwrap();
+        let matches = |entry: &str, ip, asn| Untrusted::parse(entry).unwrap().matches(ip, asn);
+        assert!(matches("AS64512", ip, Some(64512)));
+        assert!(!matches("AS64512", ip, None));
+        assert!(matches("172.20.0.0/16", ip, None));
+        assert!(!matches("172.21.0.0/16", ip, None));
+        assert!(matches("172.20.1.2", ip, None));
+        assert!(!matches("0.0.0.0/0", v6, None));
+        assert!(matches("fd42::/16", v6, None));
+    }
+}
@@ -1,37 +1,69 @@
 use crate::{
-    router::{error, headers, AppState},
+    export::{self, ImportSummary},
+    markup::TagSet,
+    router::{
+        csrf::{self, CsrfToken},
+        error, headers, throttle, two_factor, AppState,
+    },
+    storage::Images,
     templates,
-    templates::models::{Board, Flash},
+    templates::models::{
+        Board, BoardSettings, ChallengeMode, Flash, IdentityMode, ImagePolicy, IpMask,
+    },
 };
 use axum::{
-    body::Body,
-    extract::{Path, State},
-    http::{Request, Response},
+    body::{Body, StreamBody},
+    extract::{Multipart, Path, State},
+    http::{header, Request, Response},
     middleware::Next,
     response::{IntoResponse, Redirect},
     Form, TypedHeader,
 };
 use axum_sessions::extractors::{ReadableSession, WritableSession};
-use rusqlite::ErrorCode;
+use chrono::Utc;
+use color_eyre::eyre::eyre;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io;
+use subtle::ConstantTimeEq;
+use tokio::{
+    fs::{self, File},
+    io::AsyncWriteExt,
+};
+use tokio_util::io::ReaderStream;
 
 pub async fn handle_home(
     State(state): State<AppState>,
+    CsrfToken(csrf): CsrfToken,
     mut session: WritableSession,
 ) -> Result<impl IntoResponse, Response<Body>> {
-    let boards = state.db.get_boards().await.map_err(error::err_into_500)?;
+    let boards = state.db.get_boards().await.map_err(error::db_error)?;
+    let name = session.get_raw("admin").unwrap_or_default();
+    let totp = state.db.get_totp(name).await.map_err(error::db_error)?;
     let flash = session.get("flash").unwrap_or_default();
     if !matches!(flash, Flash::None) {
         session.remove("flash");
     }
-    Ok(templates::AdminHome { flash, boards })
+    Ok(templates::AdminHome {
+        flash,
+        boards,
+        new_board: BoardSettings::default(),
+        totp_enabled: totp.is_some_and(|t| t.enabled),
+        csrf,
+    })
 }
 
-pub async fn handle_loginpage(session: ReadableSession) -> impl IntoResponse {
+pub async fn handle_loginpage(
+    CsrfToken(csrf): CsrfToken,
+    session: ReadableSession,
+) -> impl IntoResponse {
     if session.get_raw("admin").is_some() {
         Err(Redirect::to("/admin").into_response())
     } else {
-        Ok(templates::Login::default())
+        Ok(templates::Login {
+            flash: Flash::None,
+            csrf,
+        })
     }
 }
 
@@ -43,21 +75,48 @@ pub struct LoginForm {
 
 pub async fn handle_login(
     State(state): State<AppState>,
+    CsrfToken(csrf): CsrfToken,
     mut session: WritableSession,
+    xforwardedfor: Option<TypedHeader<headers::XForwardedFor>>,
     Form(login_form): Form<LoginForm>,
 ) -> impl IntoResponse {
-    let Some(admin) = state.cfg.admins.iter().find(|a| a.name == login_form.user) else {
+    let ip = throttle::client_ip(xforwardedfor.as_deref());
+    let throttle = &state.login_throttle;
+    if let Some(wait) = throttle.retry_after(ip, &login_form.user) {
+        tracing::warn!("Throttled admin login for {:?} from {ip}", login_form.user);
+        let page = templates::Login {
+            flash: Flash::Error(throttle::TOO_MANY_ATTEMPTS.into()),
+            csrf,
+        };
+        return throttle::too_many_attempts(wait, page);
+    }
+
+    // the same check and message whether or not the user exists; comparing
+    // digests keeps the time from leaking how much of the password matched
+    let pass = Sha256::digest(&login_form.pass);
+    let valid =
+        state.cfg.admins.iter().any(|a| {
+            a.name == login_form.user && bool::from(Sha256::digest(&a.password).ct_eq(&pass))
+        });
+    if !valid {
+        tracing::warn!("Failed admin login for {:?} from {ip}", login_form.user);
+        throttle.record_failure(ip, &login_form.user);
         return templates::Login {
-            flash: Flash::Error("Invalid login".into()),
+            flash: Flash::Error("Invalid username or password".into()),
+            csrf,
         }
         .into_response();
+    }
+
+    let totp = match state.db.get_totp(login_form.user.clone()).await {
+        Ok(totp) => totp,
+        Err(e) => return error::db_error(e).into_response(),
     };
-    if admin.password != login_form.pass {
-        return templates::Login {
-            flash: Flash::Error("Invalid password".into()),
-        }
-        .into_response();
+    if totp.is_some_and(|t| t.enabled) {
+        two_factor::begin_login(&mut session, login_form.user);
+        return Redirect::to("/admin/login/totp").into_response();
     }
+    throttle.record_success(ip, &login_form.user);
     session.insert_raw("admin", login_form.user);
     Redirect::to("/admin").into_response()
 }
@@ -72,6 +131,48 @@ pub struct UpdateBoardForm {
     name: String,
     description: String,
     color: String,
+    #[serde(default)]
+    identity: IdentityMode,
+    #[serde(default)]
+    ip_mask: String,
+    #[serde(default)]
+    images: ImagePolicy,
+    #[serde(default)]
+    max_post_length: String,
+    #[serde(default)]
+    locked: bool,
+    #[serde(default)]
+    hidden: bool,
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    challenge: ChallengeMode,
+    #[serde(default)]
+    moderated: bool,
+}
+
+impl UpdateBoardForm {
+    fn settings(&self) -> Option<BoardSettings> {
+        let max_post_length = match self.max_post_length.trim() {
+            "" => None,
+            len => Some(len.parse().ok()?),
+        };
+        let tags = match self.tags.trim() {
+            "" => None,
+            tags => Some(TagSet::parse(tags).ok()?),
+        };
+        Some(BoardSettings {
+            identity: self.identity,
+            ip_mask: IpMask::from_name(&self.ip_mask),
+            locked: self.locked,
+            images: self.images,
+            max_post_length,
+            hidden: self.hidden,
+            tags,
+            challenge: self.challenge,
+            moderated: self.moderated,
+        })
+    }
 }
 
 pub async fn handle_createboard(
@@ -79,60 +180,247 @@ pub async fn handle_createboard(
     mut session: WritableSession,
     Form(create_form): Form<UpdateBoardForm>,
 ) -> impl IntoResponse {
+    let settings = create_form.settings().ok_or_else(error::http_400)?;
     match state
         .db
         .create_board(
             create_form.name,
             create_form.description,
-            parse_html_color(&create_form.color)?,
+            parse_html_color(&create_form.color).ok_or_else(error::http_400)?,
+            settings,
         )
         .await
     {
         Ok(_) => session
             .insert("flash", Flash::Success("Board successfully created".into()))
             .unwrap(),
-        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => {
+        Err(e) if e.is_constraint_violation() => {
             session
                 .insert("flash", Flash::Error("Board already exists".into()))
                 .unwrap();
         }
-        Err(e) => return Err(error::err_into_500(e)),
+        Err(e) => return Err(error::db_error(e)),
     }
     Ok(Redirect::to("/admin"))
 }
 
+#[derive(Deserialize)]
+pub struct DeleteBoardForm {
+    #[serde(default)]
+    archive: bool,
+}
+
+/// Asks for confirmation before a board and everything on it is deleted.
+pub async fn handle_deleteboard_page(
+    State(state): State<AppState>,
+    CsrfToken(csrf): CsrfToken,
+    Path(board_id): Path<i64>,
+) -> Result<impl IntoResponse, Response<Body>> {
+    let board = state
+        .db
+        .get_board_by_id(board_id)
+        .await
+        .map_err(error::db_error)?
+        .ok_or_else(error::http_404)?;
+    let (posts, images) = state
+        .db
+        .count_board_posts(board_id)
+        .await
+        .map_err(error::db_error)?;
+    Ok(templates::DeleteBoard {
+        board,
+        posts,
+        images,
+        can_archive: state.cfg.archive_path.is_some(),
+        csrf,
+    })
+}
+
 pub async fn handle_deleteboard(
     State(state): State<AppState>,
     mut session: WritableSession,
     Path(board_id): Path<i64>,
+    Form(form): Form<DeleteBoardForm>,
 ) -> Result<impl IntoResponse, Response<Body>> {
-    state
+    let board = state
+        .db
+        .get_board_by_id(board_id)
+        .await
+        .map_err(error::db_error)?
+        .ok_or_else(error::http_404)?;
+    let images = state
         .db
         .delete_board(board_id)
         .await
-        .map_err(error::err_into_500)?;
+        .map_err(error::db_error)?
+        .ok_or_else(error::http_404)?;
+    let archive = match &state.cfg.archive_path {
+        Some(path) if form.archive => {
+            let stamp = Utc::now().format("%Y%m%d-%H%M%S");
+            let archive = path.join(format!("{}-{stamp}", board.name));
+            match archive_images(&state.images, &archive, &images).await {
+                Ok(()) => Some(archive),
+                Err(e) => {
+                    // the images stay around for `zhaba fsck` rather than getting lost
+                    tracing::error!("Failed to archive the images of /{}/: {e}", board.name);
+                    session
+                        .insert(
+                            "flash",
+                            Flash::Error(
+                                "Board deleted, but archiving its images failed so they were kept"
+                                    .into(),
+                            ),
+                        )
+                        .unwrap();
+                    return Ok(Redirect::to("/admin"));
+                }
+            }
+        }
+        _ => None,
+    };
+    for image in &images {
+        if let Err(e) = state.images.delete(image).await {
+            tracing::warn!("Failed to remove image {image}: {e}");
+        }
+    }
+    let flash = match archive {
+        Some(archive) => format!(
+            "Board successfully deleted, {} images archived to {}",
+            images.len(),
+            archive.display()
+        ),
+        None => format!(
+            "Board successfully deleted along with {} images",
+            images.len()
+        ),
+    };
     session
-        .insert("flash", Flash::Success("Board successfully deleted".into()))
+        .insert("flash", Flash::Success(flash.into()))
         .unwrap();
     Ok(Redirect::to("/admin"))
 }
 
+pub async fn handle_exportboard(
+    State(state): State<AppState>,
+    Path(board_id): Path<i64>,
+) -> Result<impl IntoResponse, Response<Body>> {
+    let board = state
+        .db
+        .get_board_by_id(board_id)
+        .await
+        .map_err(error::db_error)?
+        .ok_or_else(error::http_404)?;
+    let path = export::scratch_path();
+    let exported = export::export(&state.db, &state.images, board.name.clone(), &path).await;
+    let file = match exported {
+        Ok(Some(_)) => File::open(&path).await.map_err(error::err_into_500),
+        Ok(None) => Err(error::http_404()),
+        Err(e) => Err(error::err_into_500(e)),
+    };
+    // an open file stays readable once it's unlinked
+    let _ = fs::remove_file(&path).await;
+    let file = file?;
+    let filename = if board
+        .name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b))
+    {
+        board.name
+    } else {
+        "board".into()
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-tar".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{filename}.tar\""),
+            ),
+        ],
+        StreamBody::new(ReaderStream::new(file)),
+    ))
+}
+
+pub async fn handle_importboard(
+    State(state): State<AppState>,
+    mut session: WritableSession,
+    mut mp: Multipart,
+) -> Result<impl IntoResponse, Response<Body>> {
+    csrf::check_upload(&session, &mut mp).await?;
+    let path = export::scratch_path();
+    let result = import_upload(&state, &path, mp).await;
+    let _ = fs::remove_file(&path).await;
+    let flash = match result {
+        Ok(summary) => Flash::Success(summary.to_string().into()),
+        Err(e) => Flash::Error(format!("Import failed: {e}").into()),
+    };
+    session.insert("flash", flash).unwrap();
+    Ok(Redirect::to("/admin"))
+}
+
+async fn import_upload(
+    state: &AppState,
+    path: &std::path::Path,
+    mut mp: Multipart,
+) -> color_eyre::Result<ImportSummary> {
+    let mut name = None;
+    let mut uploaded = false;
+    while let Some(mut field) = mp.next_field().await? {
+        match field.name() {
+            Some("archive") => {
+                let mut file = File::create(path).await?;
+                while let Some(chunk) = field.chunk().await? {
+                    file.write_all(&chunk).await?;
+                }
+                file.flush().await?;
+                uploaded = true;
+            }
+            Some("name") => {
+                let text = field.text().await?;
+                name = Some(text.trim().to_string()).filter(|n| !n.is_empty());
+            }
+            _ => {}
+        }
+    }
+    if !uploaded {
+        return Err(eyre!("no export was uploaded"));
+    }
+    export::import(&state.db, &state.images, path, name).await
+}
+
+async fn archive_images(
+    storage: &Images,
+    archive: &std::path::Path,
+    images: &[String],
+) -> io::Result<()> {
+    fs::create_dir_all(archive).await?;
+    for image in images {
+        if let Some(mut object) = storage.get(image, None).await? {
+            let mut file = File::create(archive.join(image)).await?;
+            tokio::io::copy(&mut object.body, &mut file).await?;
+        }
+    }
+    Ok(())
+}
+
 pub async fn handle_updateboard(
     State(state): State<AppState>,
     mut session: WritableSession,
     Path(board_id): Path<i64>,
     Form(update_form): Form<UpdateBoardForm>,
 ) -> Result<impl IntoResponse, Response<Body>> {
+    let settings = update_form.settings().ok_or_else(error::http_400)?;
     state
         .db
         .update_board(Board {
             id: board_id,
             name: update_form.name,
             description: update_form.description,
-            color: parse_html_color(&update_form.color)?,
+            color: parse_html_color(&update_form.color).ok_or_else(error::http_400)?,
+            settings,
         })
         .await
-        .map_err(error::err_into_500)?;
+        .map_err(error::db_error)?;
     session
         .insert("flash", Flash::Success("Board successfully updated".into()))
         .unwrap();
@@ -145,15 +433,41 @@ pub async fn handle_deletepost(
     TypedHeader(headers::Referer(referer)): TypedHeader<headers::Referer>,
     Path(post_id): Path<i64>,
 ) -> Result<impl IntoResponse, Response<Body>> {
-    let deleted = state
+    let Some(image) = state
+        .db
+        .delete_post(post_id)
+        .await
+        .map_err(error::db_error)?
+    else {
+        return Err(error::http_404());
+    };
+    // once the post is gone, so a failed delete leaves an orphan for `zhaba fsck`
+    if let Some(image) = image {
+        if let Err(e) = state.images.delete(&image).await {
+            tracing::warn!("Failed to remove image {image}: {e}");
+        }
+    }
+    session
+        .insert("flash", Flash::Success("Post successfully deleted".into()))
+        .unwrap();
+    Ok(Redirect::to(&referer))
+}
+
+pub async fn handle_approvepost(
+    State(state): State<AppState>,
+    mut session: WritableSession,
+    TypedHeader(headers::Referer(referer)): TypedHeader<headers::Referer>,
+    Path(post_id): Path<i64>,
+) -> Result<impl IntoResponse, Response<Body>> {
+    let approved = state
         .db
-        .delete_post(post_id, state.cfg.image_path.clone())
+        .approve_post(post_id)
         .await
-        .map_err(error::err_into_500)?;
+        .map_err(error::db_error)?;
 
-    if deleted {
+    if approved {
         session
-            .insert("flash", Flash::Success("Post successfully deleted".into()))
+            .insert("flash", Flash::Success("Post successfully approved".into()))
             .unwrap();
         Ok(Redirect::to(&referer))
     } else {
@@ -175,7 +489,6 @@ pub async fn auth_middleware<B>(
     }
 }
 
-fn parse_html_color(color: &str) -> Result<u32, Response<Body>> {
-    let color_hex = color.get(1..=6).ok_or_else(error::http_400)?;
-    u32::from_str_radix(color_hex, 16).map_err(|_| error::http_400())
+fn parse_html_color(color: &str) -> Option<u32> {
+    u32::from_str_radix(color.get(1..=6)?, 16).ok()
 }
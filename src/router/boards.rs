@@ -1,4 +1,4 @@
-use std::{net::Ipv4Addr, num::ParseIntError, sync::OnceLock};
+use std::num::ParseIntError;
 
 use axum::{
     body::{Body, Bytes},
@@ -9,7 +9,6 @@ use axum::{
 };
 
 use axum_sessions::extractors::WritableSession;
-use bbscope::{BBCode, BBCodeTagConfig};
 use chrono::{Datelike, Months, NaiveDate, Utc};
 use rand::{
     distributions::{Alphanumeric, DistString},
@@ -18,38 +17,38 @@ use rand::{
 use serde::Deserialize;
 
 use crate::{
-    database::{CreatePostResult, InsertImage},
-    imghdr,
-    templates::{self, models::Flash},
+    database::{CreatePostResult, InsertImage, NewPost},
+    identity, imghdr, markup,
+    templates::{
+        self,
+        models::{FilterAction, Flash, ImagePolicy, IpMask},
+    },
     whois::{self},
 };
 
-use super::{error, headers, AppState};
-
-static BBCODE: OnceLock<BBCode> = OnceLock::new();
-
-fn init_bbcode() -> BBCode {
-    let config = BBCodeTagConfig {
-        accepted_tags: vec![
-            "b".into(),
-            "i".into(),
-            "sup".into(),
-            "sub".into(),
-            "u".into(),
-            "s".into(),
-        ],
-        ..Default::default()
-    };
-    BBCode::from_config(config, None).unwrap()
-}
+use super::{csrf, error, headers, throttle, AppState};
+
+const MAX_NAME_LENGTH: usize = 32;
 
 pub async fn handle_home(
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, Response<Body>> {
-    let boards = state.db.get_boards().await.map_err(error::err_into_500)?;
+    let mut boards = state.db.get_boards().await.map_err(error::db_error)?;
+    boards.retain(|b| !b.settings.hidden);
     Ok(templates::Index { boards })
 }
 
+pub async fn handle_about(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, Response<Body>> {
+    let mut boards = state.db.get_boards().await.map_err(error::db_error)?;
+    boards.retain(|b| !b.settings.hidden && b.settings.tags.is_some());
+    Ok(templates::About {
+        examples: markup::examples(state.cfg.bbcode_tags),
+        boards,
+    })
+}
+
 pub async fn handle_post(
     State(state): State<AppState>,
     Path(board_name): Path<String>,
@@ -58,6 +57,33 @@ pub async fn handle_post(
     mp: Multipart,
 ) -> Result<Redirect, Response<Body>> {
     let redirect_uri = format!("/{board_name}");
+    let Some(board) = state
+        .db
+        .get_board_by_name(board_name.clone())
+        .await
+        .map_err(error::db_error)?
+    else {
+        return Err(error::http_404());
+    };
+    if board.settings.locked {
+        session
+            .insert("flash", Flash::Error("This board is locked".into()))
+            .unwrap();
+        return Ok(Redirect::to(&redirect_uri));
+    }
+    // the address the proxy saw, since anything before it is up to the client
+    let ip = throttle::client_ip(Some(&xforwardedfor));
+    let ban = state
+        .db
+        .get_ban(ip.to_string())
+        .await
+        .map_err(error::db_error)?;
+    if ban.is_some() {
+        session
+            .insert("flash", Flash::Error("You are banned from posting".into()))
+            .unwrap();
+        return Ok(Redirect::to(&redirect_uri));
+    }
     let post = read_post_mp(mp).await.map_err(error::err_into_500)?;
     let Some(content) = post.content else {
         return Err(error::http_400());
@@ -68,32 +94,35 @@ pub async fn handle_post(
             .unwrap();
         return Ok(Redirect::to(&redirect_uri));
     }
-    if content.len() > state.cfg.max_post_length {
+    let max_post_length = board
+        .settings
+        .max_post_length
+        .unwrap_or(state.cfg.max_post_length);
+    if content.len() > max_post_length {
         session
             .insert(
                 "flash",
-                Flash::Error(
-                    format!(
-                        "Post content too long (max {} chars)",
-                        state.cfg.max_post_length
-                    )
-                    .into(),
-                ),
+                Flash::Error(format!("Post content too long (max {max_post_length} chars)").into()),
+            )
+            .unwrap();
+        return Ok(Redirect::to(&redirect_uri));
+    }
+    let (name, tripcode) = identity::parse_name(
+        post.name.as_deref().unwrap_or_default(),
+        &state.cfg.tripcode_secret(),
+    );
+    if name
+        .as_ref()
+        .is_some_and(|n| n.chars().count() > MAX_NAME_LENGTH)
+    {
+        session
+            .insert(
+                "flash",
+                Flash::Error(format!("Name too long (max {MAX_NAME_LENGTH} chars)").into()),
             )
             .unwrap();
         return Ok(Redirect::to(&redirect_uri));
     }
-    let content = BBCODE.get_or_init(init_bbcode).parse(&content);
-
-    let ip = xforwardedfor
-        .0
-        .into_iter()
-        .next()
-        .unwrap_or(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED))
-        .to_string();
-    let whois = whois::whois(&state.cfg.whois_server, &ip)
-        .await
-        .map_err(error::err_into_500)?;
 
     let image = if let Some(bytes) = post.image {
         if bytes.is_empty() {
@@ -103,7 +132,7 @@ pub async fn handle_post(
             filename.push_str(ext);
             Some(InsertImage {
                 bytes,
-                directory: state.cfg.image_path.clone(),
+                images: state.images.clone(),
                 filename,
             })
         } else {
@@ -119,6 +148,18 @@ pub async fn handle_post(
         None
     };
 
+    let image_error = match (board.settings.images, &image) {
+        (ImagePolicy::Required, None) => Some("Posts on this board require an image"),
+        (ImagePolicy::Forbidden, Some(_)) => Some("Images are not allowed on this board"),
+        _ => None,
+    };
+    if let Some(image_error) = image_error {
+        session
+            .insert("flash", Flash::Error(image_error.into()))
+            .unwrap();
+        return Ok(Redirect::to(&redirect_uri));
+    }
+
     let reply = post.reply.transpose();
     if reply.is_err() {
         session
@@ -127,11 +168,96 @@ pub async fn handle_post(
         return Ok(Redirect::to(&redirect_uri));
     }
 
+    let filters = state
+        .filters
+        .get(&state.db)
+        .await
+        .map_err(error::db_error)?;
+    let verdict = filters.apply(&content);
+    let content = verdict.content;
+    match verdict.action {
+        Some(FilterAction::Ban) => {
+            let filter = verdict
+                .matched
+                .iter()
+                .find(|f| f.action == FilterAction::Ban);
+            let pattern = &filter.unwrap().pattern;
+            tracing::warn!("Banning {ip} for a post matching filter {pattern:?}");
+            let duration = Some(state.cfg.anti_spam.filter_ban_secs).filter(|d| *d != 0);
+            state
+                .db
+                .create_ban(
+                    ip.to_string(),
+                    format!("Matched filter {pattern:?}"),
+                    duration,
+                )
+                .await
+                .map_err(error::db_error)?;
+            session
+                .insert("flash", Flash::Error("You are banned from posting".into()))
+                .unwrap();
+            return Ok(Redirect::to(&redirect_uri));
+        }
+        Some(FilterAction::Reject) => {
+            session
+                .insert(
+                    "flash",
+                    Flash::Error("Your post was rejected by a filter".into()),
+                )
+                .unwrap();
+            return Ok(Redirect::to(&redirect_uri));
+        }
+        _ => {}
+    }
+    // after everything that sends a post back to be fixed, so it doesn't need a
+    // new solution, but before the lookups that cost something to make
+    if state.challenges.required(board.settings.challenge, ip) {
+        let solved = match (&post.challenge, &post.response) {
+            (Some(token), Some(response)) => {
+                let now = Utc::now().timestamp().unsigned_abs();
+                state.challenges.verify(token, response, now)
+            }
+            _ => false,
+        };
+        if !solved {
+            session
+                .insert(
+                    "flash",
+                    Flash::Error("Anti-spam challenge failed, please try again".into()),
+                )
+                .unwrap();
+            return Ok(Redirect::to(&redirect_uri));
+        }
+    }
+
+    // the post goes up without whois data rather than not at all
+    let whois = whois::whois(&state.cfg.whois_server, &ip.to_string())
+        .await
+        .unwrap_or_else(|e| {
+            tracing::warn!("Whois lookup for {ip} failed: {e}");
+            None
+        });
+    let untrusted = state.db.get_untrusted().await.map_err(error::db_error)?;
+    let asn = whois.as_ref().map(|w| w.asn);
+    let pending = verdict.action == Some(FilterAction::Hold)
+        || board.settings.moderated
+        || untrusted.iter().any(|u| u.entry.matches(ip, asn));
+
     if let CreatePostResult::InvalidReply = state
         .db
-        .create_post(board_name, content, ip, whois, reply.unwrap(), image)
+        .create_post(NewPost {
+            board: board_name,
+            content,
+            ip: ip.to_string(),
+            whois,
+            name,
+            tripcode,
+            reply: reply.unwrap(),
+            image,
+            pending,
+        })
         .await
-        .map_err(error::err_into_500)?
+        .map_err(error::db_error)?
     {
         session
             .insert(
@@ -139,7 +265,16 @@ pub async fn handle_post(
                 Flash::Error("Couldn't find the post you are replying to".into()),
             )
             .unwrap();
+    } else if pending {
+        state.challenges.record_post(ip);
+        session
+            .insert(
+                "flash",
+                Flash::Success("Your post is awaiting approval".into()),
+            )
+            .unwrap();
     } else {
+        state.challenges.record_post(ip);
         session
             .insert(
                 "flash",
@@ -159,8 +294,11 @@ pub struct DateRangeQuery {
 
 pub struct PostResult {
     pub content: Option<String>,
+    pub name: Option<String>,
     pub image: Option<Bytes>,
     pub reply: Option<Result<u64, ParseIntError>>,
+    pub challenge: Option<String>,
+    pub response: Option<String>,
 }
 
 pub async fn handle_view(
@@ -168,6 +306,7 @@ pub async fn handle_view(
     mut session: WritableSession,
     Path(board_name): Path<String>,
     range: Query<DateRangeQuery>,
+    xforwardedfor: Option<TypedHeader<headers::XForwardedFor>>,
 ) -> impl IntoResponse {
     let flash = session.get("flash").unwrap_or_default();
     if !matches!(flash, Flash::None) {
@@ -177,11 +316,13 @@ pub async fn handle_view(
         .db
         .get_board_by_name(board_name)
         .await
-        .map_err(error::err_into_500)?;
+        .map_err(error::db_error)?;
 
     let Some(board) = board else {
         return Err(error::http_404());
     };
+    // only now, so requests for boards that don't exist don't start a session
+    let csrf = csrf::token(&mut session);
 
     let now = Utc::now();
     let year = range.y.unwrap_or_else(|| now.year());
@@ -197,30 +338,68 @@ pub async fn handle_view(
         .unwrap()
         .timestamp() as u64;
 
-    let posts = state
+    let admin = session.get_raw("admin");
+    let mut posts = state
         .db
-        .get_posts(board.id, start_ts..end_ts)
+        .get_posts(board.id, start_ts..end_ts, admin.is_some())
         .await
-        .map_err(error::err_into_500)?;
+        .map_err(error::db_error)?;
+    let ip_mask = if admin.is_some() {
+        IpMask::Show
+    } else {
+        board.settings.ip_mask.unwrap_or(state.cfg.ip_mask)
+    };
+    identity::assign(&board, &mut posts, &state.cfg.poster_id_salt(), ip_mask);
+
+    let tags = board.settings.tags.unwrap_or(state.cfg.bbcode_tags);
+    let mut references = Vec::new();
+    for post in &mut posts {
+        markup::render_post(tags, post);
+        references.extend(markup::references(&post.content));
+    }
+    references.sort_unstable();
+    references.dedup();
+    let resolved = state
+        .db
+        .resolve_posts(references, admin.is_some())
+        .await
+        .map_err(error::db_error)?;
+    for post in &mut posts {
+        post.content = markup::link_references(&post.content, &resolved);
+    }
+
+    let ip = throttle::client_ip(xforwardedfor.as_deref());
+    let challenge = state
+        .challenges
+        .required(board.settings.challenge, ip)
+        .then(|| state.challenges.issue(now.timestamp().unsigned_abs()));
 
     Ok(templates::BoardView {
+        challenge,
         board,
         year,
-        admin: session.get_raw("admin"),
+        admin,
         flash,
         posts,
         month,
+        csrf,
     })
 }
 
 async fn read_post_mp(mut mp: Multipart) -> color_eyre::Result<PostResult> {
     let mut content = None;
+    let mut name = None;
     let mut image = None;
     let mut reply = None;
+    let mut challenge = None;
+    let mut response = None;
     while let Some(field) = mp.next_field().await? {
         match field.name() {
             Some("content") => content = Some(field.text().await?),
+            Some("name") => name = Some(field.text().await?),
             Some("image") => image = Some(field.bytes().await?),
+            Some("challenge") => challenge = Some(field.text().await?),
+            Some("response") => response = Some(field.text().await?),
             Some("reply") => {
                 reply = {
                     let content = field.text().await?;
@@ -236,7 +415,10 @@ async fn read_post_mp(mut mp: Multipart) -> color_eyre::Result<PostResult> {
     }
     Ok(PostResult {
         content,
+        name,
         image,
         reply,
+        challenge,
+        response,
     })
 }
@@ -0,0 +1,252 @@
+use axum::{
+    async_trait,
+    body::{Body, Bytes, HttpBody},
+    extract::{FromRequest, FromRequestParts, Multipart, State},
+    http::{header, request::Parts, HeaderMap, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Form, RequestExt,
+};
+use axum_sessions::{async_session::Session, SessionHandle};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use serde::Deserialize;
+
+use crate::config::Csrf;
+
+use super::{error, AppState};
+
+const SESSION_KEY: &str = "csrf";
+const TOKEN_LENGTH: usize = 32;
+
+/// The session's CSRF token, embedded in every form as a hidden `csrf` field.
+/// It's only made when a page with a form asks for it, so requests for anything
+/// else don't start a session. Handlers have to take it before `ReadableSession`
+/// or `WritableSession`, which hold on to the session it's written to.
+#[derive(Clone)]
+pub struct CsrfToken(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CsrfToken {
+    type Rejection = Response<Body>;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let handle = parts
+            .extensions
+            .get::<SessionHandle>()
+            .ok_or_else(|| error::err_into_500("Session layer is not installed"))?;
+        let mut session = handle.write().await;
+        Ok(Self(token(&mut session)))
+    }
+}
+
+/// The session's token, made now if it doesn't have one yet. For handlers that
+/// only render a form some of the time, and already hold the session.
+pub fn token(session: &mut Session) -> String {
+    if let Some(token) = session.get(SESSION_KEY) {
+        return token;
+    }
+    let token = Alphanumeric.sample_string(&mut thread_rng(), TOKEN_LENGTH);
+    session.insert(SESSION_KEY, &token).unwrap();
+    token
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    csrf: Option<String>,
+}
+
+/// Rejects state-changing requests that don't submit the session's token,
+/// unless they pass the `Origin`/`Referer` fallback. The body has to be
+/// buffered to find the token in it.
+pub async fn middleware(
+    State(state): State<AppState>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, Response> {
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        return Ok(next.run(request).await);
+    }
+
+    let token = session_token(&request).await?;
+    let buffered = match request.with_limited_body() {
+        Ok(limited) => buffer(limited).await,
+        Err(unlimited) => buffer(unlimited).await,
+    };
+    let Some((request, body)) = buffered else {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    };
+    let submitted = submitted_token(request.headers(), body).await;
+    let valid = match (submitted, token) {
+        (Some(submitted), Some(token)) => constant_time_eq(submitted.as_bytes(), token.as_bytes()),
+        _ => false,
+    };
+    let fallback =
+        state.cfg.csrf.origin_fallback && origin_allowed(&state.cfg.csrf, request.headers());
+    if !valid && !fallback {
+        tracing::warn!(
+            "Rejected {} {} without a valid CSRF token",
+            request.method(),
+            request.uri().path()
+        );
+        return Err(error::http_403().into_response());
+    }
+    Ok(next.run(request).await)
+}
+
+/// Checks the token of an upload that's streamed to its handler rather than
+/// buffered, for routes left out of `middleware`. It has to be the form's first
+/// field, so it's there before the upload. There's no `Origin` fallback, the
+/// form always has a token.
+pub async fn check_upload(
+    session: &Session,
+    multipart: &mut Multipart,
+) -> Result<(), Response<Body>> {
+    let submitted = match multipart.next_field().await {
+        Ok(Some(field)) if field.name() == Some(SESSION_KEY) => field.text().await.ok(),
+        _ => None,
+    };
+    let token: Option<String> = session.get(SESSION_KEY);
+    let valid = match (submitted, token) {
+        (Some(submitted), Some(token)) => constant_time_eq(submitted.as_bytes(), token.as_bytes()),
+        _ => false,
+    };
+    if !valid {
+        tracing::warn!("Rejected an upload without a valid CSRF token");
+        return Err(error::http_403());
+    }
+    Ok(())
+}
+
+/// The token a form on an earlier page was given, `None` if no page asked for one.
+async fn session_token(request: &Request<Body>) -> Result<Option<String>, Response> {
+    let handle = request
+        .extensions()
+        .get::<SessionHandle>()
+        .ok_or_else(|| error::err_into_500("Session layer is not installed").into_response())?;
+    let session = handle.read().await;
+    Ok(session.get(SESSION_KEY))
+}
+
+/// Reads the whole body so the token can be checked, returning a request with
+/// the same body for the handler. `None` if the body is over the upload limit.
+async fn buffer<B>(request: Request<B>) -> Option<(Request<Body>, Bytes)>
+where
+    B: HttpBody<Data = Bytes> + Unpin,
+{
+    let (parts, mut body) = request.into_parts();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.ok()?);
+    }
+    let bytes = Bytes::from(bytes);
+    Some((Request::from_parts(parts, Body::from(bytes.clone())), bytes))
+}
+
+async fn submitted_token(headers: &HeaderMap, body: Bytes) -> Option<String> {
+    let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    if content_type.starts_with("multipart/form-data") {
+        let boundary = multer::parse_boundary(content_type).ok()?;
+        let mut multipart = multer::Multipart::new(Body::from(body), boundary);
+        while let Some(field) = multipart.next_field().await.ok()? {
+            if field.name() == Some(SESSION_KEY) {
+                return field.text().await.ok();
+            }
+        }
+        None
+    } else {
+        let request = Request::builder()
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .ok()?;
+        let Form(form) = Form::<TokenForm>::from_request(request, &()).await.ok()?;
+        form.csrf
+    }
+}
+
+/// Checks `Origin`, or the origin of `Referer` if there's none, against the
+/// configured origins or the request's own `Host`.
+fn origin_allowed(cfg: &Csrf, headers: &HeaderMap) -> bool {
+    let origin = match headers.get(header::ORIGIN) {
+        Some(origin) => origin.to_str().ok(),
+        None => headers
+            .get(header::REFERER)
+            .and_then(|r| r.to_str().ok())
+            .and_then(origin_of),
+    };
+    let Some(origin) = origin.filter(|o| *o != "null") else {
+        return false;
+    };
+    if !cfg.allowed_origins.is_empty() {
+        return cfg.allowed_origins.iter().any(|o| o == origin);
+    }
+    let host = headers.get(header::HOST).and_then(|h| h.to_str().ok());
+    origin
+        .split_once("://")
+        .is_some_and(|(_, authority)| Some(authority) == host)
+}
+
+fn origin_of(url: &str) -> Option<&str> {
+    let authority_start = url.find("://")? + 3;
+    let end = url[authority_start..]
+        .find(['/', '?', '#'])
+        .map_or(url.len(), |i| authority_start + i);
+    Some(&url[..end])
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn headers(pairs: &[(header::HeaderName, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn origin_matches_host() {
+        let cfg = Csrf::default();
+        let same = headers(&[
+            (header::HOST, "zhaba.dn42"),
+            (header::ORIGIN, "https://zhaba.dn42"),
+        ]);
+        assert!(origin_allowed(&cfg, &same));
+        let other = headers(&[
+            (header::HOST, "zhaba.dn42"),
+            (header::ORIGIN, "https://evil.dn42"),
+        ]);
+        assert!(!origin_allowed(&cfg, &other));
+        let opaque = headers(&[(header::HOST, "zhaba.dn42"), (header::ORIGIN, "null")]);
+        assert!(!origin_allowed(&cfg, &opaque));
+        assert!(!origin_allowed(
+            &cfg,
+            &headers(&[(header::HOST, "zhaba.dn42")])
+        ));
+    }
+
+    #[test]
+    fn referer_and_allowed_origins() {
+        let cfg = Csrf {
+            origin_fallback: true,
+            allowed_origins: vec!["https://zhaba.dn42".into()],
+        };
+        let referer = headers(&[(header::REFERER, "https://zhaba.dn42/b?y=2023#5")]);
+        assert!(origin_allowed(&cfg, &referer));
+        let lookalike = headers(&[(header::REFERER, "https://zhaba.dn42.evil/b")]);
+        assert!(!origin_allowed(&cfg, &lookalike));
+    }
+}
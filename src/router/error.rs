@@ -1,12 +1,19 @@
 use axum::{
     body::Body,
-    http::{Response, StatusCode},
+    http::{header, Response, StatusCode},
 };
 use std::fmt::Debug;
 
+use crate::database::DbError;
+
 pub const HTML_400: &[u8] = include_bytes!("html/400.html");
+pub const HTML_403: &[u8] = include_bytes!("html/403.html");
 pub const HTML_404: &[u8] = include_bytes!("html/404.html");
 pub const HTML_500: &[u8] = include_bytes!("html/500.html");
+pub const HTML_503: &[u8] = include_bytes!("html/503.html");
+
+// what a 503 tells clients to wait before retrying
+const RETRY_AFTER_SECS: u64 = 5;
 
 pub fn http_404() -> Response<Body> {
     Response::builder()
@@ -22,6 +29,13 @@ pub fn http_400() -> Response<Body> {
         .unwrap()
 }
 
+pub fn http_403() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::from(HTML_403))
+        .unwrap()
+}
+
 pub fn err_into_500<T: Debug>(e: T) -> Response<Body> {
     tracing::error!("{e:?}");
     Response::builder()
@@ -29,3 +43,21 @@ pub fn err_into_500<T: Debug>(e: T) -> Response<Body> {
         .body(Body::from(HTML_500))
         .unwrap()
 }
+
+pub fn http_503() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(header::RETRY_AFTER, RETRY_AFTER_SECS)
+        .body(Body::from(HTML_503))
+        .unwrap()
+}
+
+/// A 503 when the database is overloaded or gone, a 500 for anything else.
+pub fn db_error(e: DbError) -> Response<Body> {
+    if e.is_unavailable() {
+        tracing::warn!("{e}");
+        http_503()
+    } else {
+        err_into_500(e)
+    }
+}
@@ -0,0 +1,148 @@
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::Response,
+    response::{IntoResponse, Redirect},
+    Form,
+};
+use axum_sessions::extractors::WritableSession;
+use serde::Deserialize;
+
+use crate::{
+    filter::{self, Verdict},
+    templates::{
+        self,
+        models::{Filter, FilterAction, Flash, PatternKind},
+    },
+};
+
+use super::{csrf::CsrfToken, error, AppState};
+
+#[derive(Deserialize)]
+pub struct FilterForm {
+    pattern: String,
+    #[serde(default)]
+    kind: PatternKind,
+    #[serde(default)]
+    action: FilterAction,
+    #[serde(default)]
+    replacement: String,
+}
+
+#[derive(Deserialize)]
+pub struct TestForm {
+    text: String,
+}
+
+async fn page(
+    state: &AppState,
+    flash: Flash,
+    test: String,
+    verdict: Option<Verdict>,
+    csrf: String,
+) -> Result<templates::FilterAdmin, Response<Body>> {
+    let filters = state.db.get_filters().await.map_err(error::db_error)?;
+    let bans = state.db.get_bans().await.map_err(error::db_error)?;
+    Ok(templates::FilterAdmin {
+        flash,
+        filters,
+        bans,
+        test,
+        verdict,
+        csrf,
+    })
+}
+
+pub async fn handle_home(
+    State(state): State<AppState>,
+    CsrfToken(csrf): CsrfToken,
+    mut session: WritableSession,
+) -> Result<impl IntoResponse, Response<Body>> {
+    let flash = session.get("flash").unwrap_or_default();
+    if !matches!(flash, Flash::None) {
+        session.remove("flash");
+    }
+    drop(session);
+    page(&state, flash, String::new(), None, csrf).await
+}
+
+pub async fn handle_create(
+    State(state): State<AppState>,
+    mut session: WritableSession,
+    Form(form): Form<FilterForm>,
+) -> Result<impl IntoResponse, Response<Body>> {
+    let flash = if form.pattern.is_empty() {
+        Flash::Error("Pattern cannot be empty".into())
+    } else if let Err(e) = filter::compile(form.kind, &form.pattern) {
+        Flash::Error(format!("Invalid pattern: {e}").into())
+    } else {
+        state
+            .db
+            .create_filter(Filter {
+                id: 0,
+                pattern: form.pattern,
+                kind: form.kind,
+                action: form.action,
+                replacement: form.replacement,
+            })
+            .await
+            .map_err(error::db_error)?;
+        state.filters.invalidate();
+        Flash::Success("Filter successfully created".into())
+    };
+    session.insert("flash", flash).unwrap();
+    Ok(Redirect::to("/admin/filters"))
+}
+
+pub async fn handle_delete(
+    State(state): State<AppState>,
+    mut session: WritableSession,
+    Path(filter_id): Path<i64>,
+) -> Result<impl IntoResponse, Response<Body>> {
+    let deleted = state
+        .db
+        .delete_filter(filter_id)
+        .await
+        .map_err(error::db_error)?;
+    if !deleted {
+        return Err(error::http_404());
+    }
+    state.filters.invalidate();
+    session
+        .insert(
+            "flash",
+            Flash::Success("Filter successfully deleted".into()),
+        )
+        .unwrap();
+    Ok(Redirect::to("/admin/filters"))
+}
+
+/// Shows what the current filters would do to a post, without posting it.
+pub async fn handle_test(
+    State(state): State<AppState>,
+    CsrfToken(csrf): CsrfToken,
+    Form(form): Form<TestForm>,
+) -> Result<impl IntoResponse, Response<Body>> {
+    let filters = state
+        .filters
+        .get(&state.db)
+        .await
+        .map_err(error::db_error)?;
+    let verdict = filters.apply(&form.text);
+    page(&state, Flash::None, form.text, Some(verdict), csrf).await
+}
+
+pub async fn handle_deleteban(
+    State(state): State<AppState>,
+    mut session: WritableSession,
+    Path(ban_id): Path<i64>,
+) -> Result<impl IntoResponse, Response<Body>> {
+    let deleted = state.db.delete_ban(ban_id).await.map_err(error::db_error)?;
+    if !deleted {
+        return Err(error::http_404());
+    }
+    session
+        .insert("flash", Flash::Success("Ban successfully lifted".into()))
+        .unwrap();
+    Ok(Redirect::to("/admin/filters"))
+}
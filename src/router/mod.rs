@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use axum::{
     extract::DefaultBodyLimit,
+    http::{header, HeaderValue},
     middleware,
     routing::{get, post},
     Router,
@@ -11,50 +12,153 @@ use axum_sessions::{
         base64::{self, URL_SAFE_NO_PAD},
         MemoryStore,
     },
-    SessionLayer,
+    PersistencePolicy, SessionLayer,
 };
 use color_eyre::Result;
+use tower_http::set_header::SetResponseHeaderLayer;
 
-use crate::{config::Config, database::ExecutorConnection, templates};
+use crate::{
+    challenge::Challenges, config::Config, database::ExecutorConnection, filter::Filters,
+    storage::Images,
+};
+
+use self::throttle::LoginThrottle;
 
 mod admin;
 mod boards;
+mod csrf;
 mod error;
+mod filters;
 mod headers;
+mod queue;
 mod static_files;
+mod throttle;
+mod two_factor;
 
 #[derive(Clone)]
 pub struct AppState {
     db: ExecutorConnection,
     cfg: Arc<Config>,
+    images: Images,
+    login_throttle: Arc<LoginThrottle>,
+    challenges: Arc<Challenges>,
+    filters: Arc<Filters>,
 }
 
-pub fn build(db: ExecutorConnection, cfg: Arc<Config>, store: MemoryStore) -> Result<Router> {
+pub fn build(
+    db: ExecutorConnection,
+    cfg: Arc<Config>,
+    images: Images,
+    store: MemoryStore,
+) -> Result<Router> {
     let secret = base64::decode_config(&cfg.cookie_secret, URL_SAFE_NO_PAD)?;
+    let headers = &cfg.security_headers;
+    let csp = HeaderValue::from_str(&headers.content_security_policy)?;
+    let referrer_policy = HeaderValue::from_str(&headers.referrer_policy)?;
+    let frame_options = HeaderValue::from_str(&headers.frame_options)?;
+    let hsts = headers
+        .hsts_max_age
+        .map(|age| HeaderValue::from_str(&format!("max-age={age}; includeSubDomains")))
+        .transpose()?;
 
     let admin_router = Router::new()
         .route("/admin", get(admin::handle_home))
         .route("/admin/board/create", post(admin::handle_createboard))
-        .route("/admin/board/:b/delete", post(admin::handle_deleteboard))
+        .route(
+            "/admin/board/:b/delete",
+            get(admin::handle_deleteboard_page).post(admin::handle_deleteboard),
+        )
         .route("/admin/board/:b/update", post(admin::handle_updateboard))
+        .route("/admin/board/:b/export", get(admin::handle_exportboard))
         .route("/admin/post/:p/delete", post(admin::handle_deletepost))
+        .route("/admin/post/:p/approve", post(admin::handle_approvepost))
+        .route("/admin/queue", get(queue::handle_home))
+        .route(
+            "/admin/untrusted/create",
+            post(queue::handle_createuntrusted),
+        )
+        .route(
+            "/admin/untrusted/:u/delete",
+            post(queue::handle_deleteuntrusted),
+        )
+        .route("/admin/filters", get(filters::handle_home))
+        .route("/admin/filters/create", post(filters::handle_create))
+        .route("/admin/filters/test", post(filters::handle_test))
+        .route("/admin/filters/:f/delete", post(filters::handle_delete))
+        .route("/admin/bans/:b/delete", post(filters::handle_deleteban))
         .route("/admin/logout", post(admin::handle_logout))
+        .route("/admin/totp/setup", post(two_factor::handle_setup))
+        .route("/admin/totp/enable", post(two_factor::handle_enable))
+        .route("/admin/totp/disable", post(two_factor::handle_disable))
         .route_layer(middleware::from_fn(admin::auth_middleware))
         .route("/admin/login", get(admin::handle_loginpage))
-        .route("/admin/login", post(admin::handle_login));
+        .route("/admin/login", post(admin::handle_login))
+        .route("/admin/login/totp", get(two_factor::handle_loginpage))
+        .route("/admin/login/totp", post(two_factor::handle_login));
 
-    let router = Router::new()
+    let state = AppState {
+        db,
+        images,
+        login_throttle: Arc::new(LoginThrottle::new(cfg.login_limits)),
+        challenges: Arc::new(Challenges::new(
+            cfg.anti_spam.clone(),
+            &cfg.challenge_secret(),
+        )),
+        filters: Arc::default(),
+        cfg,
+    };
+    let pages = Router::new()
         .route("/", get(boards::handle_home))
-        .route("/about", get(|| async { templates::About }))
+        .route("/about", get(boards::handle_about))
         .route("/:b", get(boards::handle_view))
         .route("/:b/post", post(boards::handle_post))
+        .merge(admin_router)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            csrf::middleware,
+        ))
+        // exports come with their images, so admins aren't held to the upload
+        // limit, and the handler checks the CSRF token itself so the upload is
+        // streamed rather than buffered
+        .route(
+            "/admin/board/import",
+            post(admin::handle_importboard)
+                .layer(DefaultBodyLimit::disable())
+                .route_layer(middleware::from_fn(admin::auth_middleware)),
+        )
+        // sessions are only stored once something's put in them, like a CSRF token
+        .layer(
+            SessionLayer::new(store, &secret)
+                .with_persistence_policy(PersistencePolicy::ChangedOnly),
+        );
+    // files and 404s don't need a session, so they don't get one
+    let router = Router::new()
         .route("/static/*file", get(static_files::static_handler))
         .route("/img/*file", get(static_files::image_handler))
+        .merge(pages)
         .fallback_service(get(|| async { error::http_404() }))
-        .merge(admin_router)
-        .layer(SessionLayer::new(store, &secret))
-        .layer(DefaultBodyLimit::max(cfg.max_upload_size))
-        .with_state(AppState { db, cfg });
+        .layer(DefaultBodyLimit::max(state.cfg.max_upload_size))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::CONTENT_SECURITY_POLICY,
+            csp,
+        ))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        ))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::REFERRER_POLICY,
+            referrer_policy,
+        ))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::X_FRAME_OPTIONS,
+            frame_options,
+        ))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::STRICT_TRANSPORT_SECURITY,
+            hsts,
+        ))
+        .with_state(state);
 
     Ok(router)
 }
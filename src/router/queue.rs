@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::Response,
+    response::{IntoResponse, Redirect},
+    Form,
+};
+use axum_sessions::extractors::WritableSession;
+use serde::Deserialize;
+
+use crate::{
+    markup,
+    moderation::Untrusted,
+    templates::{self, models::Flash},
+};
+
+use super::{csrf::CsrfToken, error, AppState};
+
+#[derive(Deserialize)]
+pub struct UntrustedForm {
+    entry: String,
+    #[serde(default)]
+    note: String,
+}
+
+pub async fn handle_home(
+    State(state): State<AppState>,
+    CsrfToken(csrf): CsrfToken,
+    mut session: WritableSession,
+) -> Result<impl IntoResponse, Response<Body>> {
+    let flash = session.get("flash").unwrap_or_default();
+    if !matches!(flash, Flash::None) {
+        session.remove("flash");
+    }
+    drop(session);
+    let boards: HashMap<_, _> = state
+        .db
+        .get_boards()
+        .await
+        .map_err(error::db_error)?
+        .into_iter()
+        .map(|b| (b.id as u64, b))
+        .collect();
+    let pending = state
+        .db
+        .get_pending_posts()
+        .await
+        .map_err(error::db_error)?;
+    let mut posts = Vec::with_capacity(pending.len());
+    for mut post in pending {
+        let Some(board) = boards.get(&post.board) else {
+            continue;
+        };
+        let tags = board.settings.tags.unwrap_or(state.cfg.bbcode_tags);
+        markup::render_post(tags, &mut post);
+        posts.push((board.name.clone(), post));
+    }
+    let untrusted = state.db.get_untrusted().await.map_err(error::db_error)?;
+    Ok(templates::ModerationQueue {
+        flash,
+        posts,
+        untrusted,
+        csrf,
+    })
+}
+
+pub async fn handle_createuntrusted(
+    State(state): State<AppState>,
+    mut session: WritableSession,
+    Form(form): Form<UntrustedForm>,
+) -> Result<impl IntoResponse, Response<Body>> {
+    let flash = match Untrusted::parse(&form.entry) {
+        Err(e) => Flash::Error(format!("Couldn't add entry: {e}").into()),
+        Ok(entry) => match state.db.create_untrusted(entry, form.note).await {
+            Ok(()) => Flash::Success(format!("{entry} is now untrusted").into()),
+            Err(e) if e.is_constraint_violation() => {
+                Flash::Error(format!("{entry} is already untrusted").into())
+            }
+            Err(e) => return Err(error::db_error(e)),
+        },
+    };
+    session.insert("flash", flash).unwrap();
+    Ok(Redirect::to("/admin/queue"))
+}
+
+pub async fn handle_deleteuntrusted(
+    State(state): State<AppState>,
+    mut session: WritableSession,
+    Path(untrusted_id): Path<i64>,
+) -> Result<impl IntoResponse, Response<Body>> {
+    let deleted = state
+        .db
+        .delete_untrusted(untrusted_id)
+        .await
+        .map_err(error::db_error)?;
+    if !deleted {
+        return Err(error::http_404());
+    }
+    session
+        .insert("flash", Flash::Success("Entry successfully removed".into()))
+        .unwrap();
+    Ok(Redirect::to("/admin/queue"))
+}
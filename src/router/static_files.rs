@@ -1,53 +1,186 @@
-use std::io::ErrorKind;
+use std::{io::Cursor, ops::Bound, pin::Pin, time::Duration};
 
 use askama_axum::IntoResponse;
 use axum::{
-    body::{boxed, Full, StreamBody},
+    body::{boxed, Body, Full, StreamBody},
     extract::State,
-    http::{header, StatusCode, Uri},
+    headers::{
+        CacheControl, ContentLength, ContentRange, ETag, HeaderMapExt, IfModifiedSince,
+        IfNoneMatch, IfRange, LastModified, Range,
+    },
+    http::{header, HeaderMap, StatusCode, Uri},
     response::Response,
 };
 use rust_embed::RustEmbed;
-use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio_util::io::ReaderStream;
 
+use crate::{imghdr, storage};
+
 use super::{error, AppState};
 
-pub async fn image_handler(State(state): State<AppState>, uri: Uri) -> impl IntoResponse {
-    let filename = uri
+// names are random and never reused, so the image behind one never changes
+const MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+// enough of the start of a file for `imghdr`
+const SNIFF_LEN: u64 = 16;
+
+pub async fn image_handler(
+    State(state): State<AppState>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Response, Response<Body>> {
+    let path = uri
         .path()
         .trim_start_matches('/')
         .strip_prefix("img/")
         .unwrap();
-    let path = state.cfg.image_path.join(filename);
-    let file = match File::open(path).await {
-        Ok(o) => o,
-        Err(e) if e.kind() == ErrorKind::NotFound => return Err(error::http_404()),
+    // images are linked by name, but can be asked for by their path on disk too
+    let filename = match storage::unshard(path) {
+        Some(name) if imghdr::is_image_name(name) => name,
+        _ => return Err(error::http_404()),
+    };
+    // a range can only be picked out once the length is known, so until then
+    // just enough of the start is read to tell the type
+    let range = headers.typed_get::<Range>();
+    let head_range = range.as_ref().map(|_| 0..SNIFF_LEN);
+    let object = match state.images.get(filename, head_range).await {
+        Ok(Some(object)) => object,
+        Ok(None) => return Err(error::http_404()),
         Err(e) => return Err(error::err_into_500(e)),
     };
-    let body = StreamBody::new(ReaderStream::new(file));
-    let mime = mime_guess::from_path(filename)
-        .first_or_octet_stream()
-        .to_string();
 
-    Ok(([(header::CONTENT_TYPE, mime)], body))
+    let etag: ETag = format!("\"{}-{:x}\"", &filename[..32], object.len)
+        .parse()
+        .unwrap();
+    let last_modified = object.modified.map(LastModified::from);
+    let mut validators = HeaderMap::new();
+    validators.typed_insert(etag.clone());
+    if let Some(last_modified) = last_modified {
+        validators.typed_insert(last_modified);
+    }
+    validators.typed_insert(
+        CacheControl::new()
+            .with_public()
+            .with_max_age(MAX_AGE)
+            .with_immutable(),
+    );
+    // If-Modified-Since only counts when there's no If-None-Match
+    let unchanged = match headers.typed_get::<IfNoneMatch>() {
+        Some(if_none_match) => !if_none_match.precondition_passes(&etag),
+        None => matches!(
+            (headers.typed_get::<IfModifiedSince>(), object.modified),
+            (Some(since), Some(modified)) if !since.is_modified(modified)
+        ),
+    };
+    if unchanged {
+        return Ok((StatusCode::NOT_MODIFIED, validators).into_response());
+    }
+
+    // the type comes from the contents, whatever the name says
+    let mut body = object.body;
+    let mut head = Vec::new();
+    (&mut body)
+        .take(SNIFF_LEN)
+        .read_to_end(&mut head)
+        .await
+        .map_err(error::err_into_500)?;
+    let ext = imghdr::imghdr(&head);
+    // only images can be displayed inline, anything else is downloaded
+    let disposition = match ext {
+        Some(_) => format!("inline; filename=\"{filename}\""),
+        None => format!("attachment; filename=\"{filename}\""),
+    };
+
+    // a range is only served if the image is still the one it was taken from
+    let wanted = range.as_ref().filter(|_| {
+        headers
+            .typed_get::<IfRange>()
+            .is_none_or(|if_range| !if_range.is_modified(Some(&etag), last_modified.as_ref()))
+    });
+    let (status, bytes) = match wanted.map(|r| byte_range(r, object.len)) {
+        None | Some(Ok(None)) => (StatusCode::OK, None),
+        Some(Ok(Some((start, end)))) => {
+            validators.typed_insert(ContentRange::bytes(start..=end, object.len).unwrap());
+            (StatusCode::PARTIAL_CONTENT, Some(start..end + 1))
+        }
+        Some(Err(())) => {
+            validators.typed_insert(ContentRange::unsatisfied_bytes(object.len));
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, validators).into_response());
+        }
+    };
+    let len = bytes.as_ref().map_or(object.len, |b| b.end - b.start);
+    let body: Pin<Box<dyn AsyncRead + Send>> = if range.is_none() {
+        Box::pin(Cursor::new(head).chain(body))
+    } else {
+        // only the start was read, the rest comes with a request of its own
+        match state.images.get(filename, bytes).await {
+            Ok(Some(object)) => object.body,
+            Ok(None) => return Err(error::http_404()),
+            Err(e) => return Err(error::err_into_500(e)),
+        }
+    };
+    validators.typed_insert(ContentLength(len));
+
+    Ok((
+        status,
+        validators,
+        [
+            (
+                header::CONTENT_TYPE,
+                ext.map_or("application/octet-stream", imghdr::mime),
+            ),
+            (header::ACCEPT_RANGES, "bytes"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                "default-src 'none'; sandbox",
+            ),
+        ],
+        [(header::CONTENT_DISPOSITION, disposition)],
+        StreamBody::new(ReaderStream::new(body.take(len))),
+    )
+        .into_response())
+}
+
+/// The first and last byte of a single range within `len` bytes, `None` if the
+/// whole image should be sent instead and `Err` if it can't be satisfied. Asking
+/// for several ranges at once gets the whole image.
+fn byte_range(range: &Range, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let mut ranges = range.iter();
+    let (Some(bounds), None) = (ranges.next(), ranges.next()) else {
+        return Ok(None);
+    };
+    let (start, end) = match bounds {
+        (Bound::Included(start), Bound::Included(end)) if start <= end => (start, end),
+        (Bound::Included(start), Bound::Unbounded) => (start, u64::MAX),
+        (Bound::Unbounded, Bound::Included(suffix)) if suffix != 0 => {
+            (len.saturating_sub(suffix), u64::MAX)
+        }
+        (Bound::Unbounded, Bound::Included(_)) => return Err(()),
+        _ => return Ok(None),
+    };
+    if start >= len {
+        return Err(());
+    }
+    Ok(Some((start, end.min(len - 1))))
 }
 
-pub async fn static_handler(uri: Uri) -> impl IntoResponse {
+pub async fn static_handler(uri: Uri, headers: HeaderMap) -> impl IntoResponse {
     let path = uri
         .path()
         .trim_start_matches('/')
         .strip_prefix("static/")
         .unwrap()
         .to_string();
-    StaticFile(path)
+    StaticFile(path, headers.typed_get())
 }
 
 #[derive(RustEmbed)]
 #[folder = "static/"]
 struct StaticFiles;
 
-pub struct StaticFile<T>(pub T);
+/// An embedded file, or a 304 if the `If-None-Match` has its ETag.
+pub struct StaticFile<T>(pub T, pub Option<IfNoneMatch>);
 
 impl<T> IntoResponse for StaticFile<T>
 where
@@ -58,10 +191,24 @@ where
 
         match StaticFiles::get(path.as_str()) {
             Some(content) => {
+                let hash = &content.metadata.sha256_hash()[..16];
+                let hash: String = hash.iter().map(|b| format!("{b:02x}")).collect();
+                let etag = format!("\"{hash}\"");
+                let unchanged = self.1.is_some_and(|if_none_match| {
+                    !if_none_match.precondition_passes(&etag.parse().unwrap())
+                });
+                if unchanged {
+                    return Response::builder()
+                        .status(StatusCode::NOT_MODIFIED)
+                        .header(header::ETAG, etag)
+                        .body(boxed(Full::default()))
+                        .unwrap();
+                }
                 let body = boxed(Full::from(content.data));
                 let mime = mime_guess::from_path(path).first_or_octet_stream();
                 Response::builder()
                     .header(header::CONTENT_TYPE, mime.as_ref())
+                    .header(header::ETAG, etag)
                     .body(body)
                     .unwrap()
             }
@@ -72,3 +219,29 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use axum::{headers::Header, http::HeaderValue};
+
+    use super::*;
+
+    fn range(value: &str) -> Range {
+        Range::decode(&mut iter::once(&HeaderValue::from_str(value).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn picks_byte_ranges() {
+        assert_eq!(byte_range(&range("bytes=0-9"), 100), Ok(Some((0, 9))));
+        assert_eq!(byte_range(&range("bytes=90-200"), 100), Ok(Some((90, 99))));
+        assert_eq!(byte_range(&range("bytes=50-"), 100), Ok(Some((50, 99))));
+        assert_eq!(byte_range(&range("bytes=-10"), 100), Ok(Some((90, 99))));
+        assert_eq!(byte_range(&range("bytes=-500"), 100), Ok(Some((0, 99))));
+        assert_eq!(byte_range(&range("bytes=100-"), 100), Err(()));
+        assert_eq!(byte_range(&range("bytes=-0"), 100), Err(()));
+        assert_eq!(byte_range(&range("bytes=9-0"), 100), Ok(None));
+        assert_eq!(byte_range(&range("bytes=0-1,5-6"), 100), Ok(None));
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use crate::config::LoginLimits;
+
+use super::headers::XForwardedFor;
+
+pub const TOO_MANY_ATTEMPTS: &str = "Too many failed attempts, try again later";
+
+// past this many tracked keys, expired ones are pruned on every failure, and
+// if that isn't enough the ones quiet the longest go too
+const MAX_KEYS: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    User(String),
+}
+
+struct Attempts {
+    failures: u32,
+    last: Instant,
+}
+
+/// Tracks failed admin logins per client IP and per username. Each failure past
+/// the free attempts doubles the wait before the next try, and reaching the
+/// failure limit locks the key out until it has been quiet for the lockout period.
+pub struct LoginThrottle {
+    limits: LoginLimits,
+    attempts: Mutex<HashMap<Key, Attempts>>,
+}
+
+impl LoginThrottle {
+    pub fn new(limits: LoginLimits) -> Self {
+        Self {
+            limits,
+            attempts: Mutex::default(),
+        }
+    }
+
+    /// How long until a login for `user` from `ip` is accepted again, if it's throttled.
+    pub fn retry_after(&self, ip: IpAddr, user: &str) -> Option<Duration> {
+        let attempts = self.attempts.lock().unwrap();
+        [Key::Ip(ip), Key::User(user.into())]
+            .iter()
+            .filter_map(|key| attempts.get(key))
+            .map(|a| self.delay(a.failures).saturating_sub(a.last.elapsed()))
+            .filter(|wait| !wait.is_zero())
+            .max()
+    }
+
+    pub fn record_failure(&self, ip: IpAddr, user: &str) {
+        let mut attempts = self.attempts.lock().unwrap();
+        if attempts.len() > MAX_KEYS {
+            let lockout = self.lockout();
+            attempts.retain(|_, a| a.last.elapsed() < lockout);
+        }
+        if attempts.len() > MAX_KEYS {
+            let mut oldest: Vec<_> = attempts.iter().map(|(k, a)| (a.last, k.clone())).collect();
+            oldest.sort_unstable_by_key(|(last, _)| *last);
+            for (_, key) in oldest.drain(..attempts.len() - MAX_KEYS) {
+                attempts.remove(&key);
+            }
+        }
+        for key in [Key::Ip(ip), Key::User(user.into())] {
+            let entry = attempts.entry(key).or_insert(Attempts {
+                failures: 0,
+                last: Instant::now(),
+            });
+            // keys that stayed quiet for a whole lockout period start over
+            if entry.last.elapsed() >= self.lockout() {
+                entry.failures = 0;
+            }
+            entry.failures += 1;
+            entry.last = Instant::now();
+        }
+    }
+
+    pub fn record_success(&self, ip: IpAddr, user: &str) {
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.remove(&Key::Ip(ip));
+        attempts.remove(&Key::User(user.into()));
+    }
+
+    fn lockout(&self) -> Duration {
+        Duration::from_secs(self.limits.lockout_secs)
+    }
+
+    fn delay(&self, failures: u32) -> Duration {
+        if failures >= self.limits.max_failures {
+            return self.lockout();
+        }
+        let Some(backoff) = failures.checked_sub(self.limits.free_attempts) else {
+            return Duration::ZERO;
+        };
+        let base = Duration::from_secs(self.limits.backoff_base_secs);
+        base.saturating_mul(1 << backoff.min(16))
+            .min(self.lockout())
+    }
+}
+
+/// The address login attempts are tracked under, the one the proxy appended.
+/// Entries before it come from the client and could be anything.
+pub fn client_ip(xforwardedfor: Option<&XForwardedFor>) -> IpAddr {
+    xforwardedfor
+        .and_then(|chain| chain.0.last().copied())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+/// Serves `page` with a 429 and a `Retry-After` of `wait`.
+pub fn too_many_attempts(wait: Duration, page: impl IntoResponse) -> Response {
+    let retry_after = wait.as_secs().max(1).to_string();
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after)],
+        page,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: LoginLimits = LoginLimits {
+        free_attempts: 2,
+        backoff_base_secs: 1,
+        max_failures: 6,
+        lockout_secs: 600,
+    };
+
+    #[test]
+    fn backoff_doubles_then_locks_out() {
+        let throttle = LoginThrottle::new(LIMITS);
+        let delays: Vec<_> = (0..8).map(|n| throttle.delay(n).as_secs()).collect();
+        assert_eq!(delays, [0, 0, 1, 2, 4, 8, 600, 600]);
+    }
+
+    #[test]
+    fn throttles_by_ip_and_user() {
+        let throttle = LoginThrottle::new(LIMITS);
+        let ip = IpAddr::V4(Ipv4Addr::new(172, 20, 0, 1));
+        let other_ip = IpAddr::V4(Ipv4Addr::new(172, 20, 0, 2));
+        for _ in 0..2 {
+            assert_eq!(throttle.retry_after(ip, "admin"), None);
+            throttle.record_failure(ip, "admin");
+        }
+        throttle.record_failure(ip, "admin");
+        assert!(throttle.retry_after(ip, "someone").is_some());
+        assert!(throttle.retry_after(other_ip, "admin").is_some());
+        assert_eq!(throttle.retry_after(other_ip, "someone"), None);
+
+        throttle.record_success(ip, "admin");
+        assert_eq!(throttle.retry_after(ip, "admin"), None);
+    }
+
+    #[test]
+    fn evicts_the_oldest_keys() {
+        let throttle = LoginThrottle::new(LIMITS);
+        let ip = |n: u32| IpAddr::V4(Ipv4Addr::from(0xac14_0000 + n));
+        for _ in 0..3 {
+            throttle.record_failure(ip(0), "admin");
+        }
+        for n in 1..=MAX_KEYS as u32 {
+            throttle.record_failure(ip(n), &format!("user{n}"));
+        }
+        assert!(throttle.attempts.lock().unwrap().len() <= MAX_KEYS + 2);
+        assert_eq!(throttle.retry_after(ip(0), "admin"), None);
+        assert!(throttle
+            .attempts
+            .lock()
+            .unwrap()
+            .contains_key(&Key::Ip(ip(MAX_KEYS as u32))));
+    }
+
+    #[test]
+    fn keys_on_the_proxy_appended_address() {
+        let chain = XForwardedFor(vec![
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            IpAddr::V4(Ipv4Addr::new(172, 20, 0, 1)),
+        ]);
+        let ip = client_ip(Some(&chain));
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(172, 20, 0, 1)));
+        assert_eq!(client_ip(None), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    }
+}
@@ -0,0 +1,275 @@
+use axum::{
+    body::Body,
+    extract::State,
+    response::{IntoResponse, Redirect, Response},
+    Form, TypedHeader,
+};
+use axum_sessions::{
+    async_session::Session,
+    extractors::{ReadableSession, WritableSession},
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{AdminTotp, TotpSecret},
+    templates::{self, models::Flash},
+    totp,
+};
+
+use super::{csrf::CsrfToken, error, headers, throttle, AppState};
+
+const PENDING_LOGIN: &str = "pending_login";
+// how long the code prompt stays valid after the password was accepted
+const PENDING_LOGIN_SECS: i64 = 300;
+
+/// An admin who got the password right but hasn't entered their code yet.
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    name: String,
+    since: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CodeForm {
+    code: String,
+}
+
+/// Holds the login until the second step, instead of setting the `admin` key.
+pub fn begin_login(session: &mut WritableSession, name: String) {
+    let pending = PendingLogin {
+        name,
+        since: Utc::now().timestamp(),
+    };
+    session.insert(PENDING_LOGIN, pending).unwrap();
+}
+
+fn pending_login(session: &Session) -> Option<PendingLogin> {
+    session
+        .get::<PendingLogin>(PENDING_LOGIN)
+        .filter(|p| Utc::now().timestamp() - p.since < PENDING_LOGIN_SECS)
+}
+
+fn now() -> u64 {
+    Utc::now().timestamp().unsigned_abs()
+}
+
+/// Accepts either a current TOTP code or one of the unused recovery codes,
+/// consuming whichever it was so it can't be replayed.
+async fn check_code(
+    state: &AppState,
+    name: &str,
+    totp: &AdminTotp,
+    code: &str,
+) -> Result<bool, Response<Body>> {
+    if let Some(step) = totp::verify(&totp.secret.0, code, now()) {
+        return state
+            .db
+            .use_totp_step(name.into(), step)
+            .await
+            .map_err(error::db_error);
+    }
+    let hashes = state
+        .db
+        .get_recovery_codes(name.into())
+        .await
+        .map_err(error::db_error)?;
+    // salted, so each one has to be checked
+    let code = code.to_owned();
+    let matched = tokio::task::spawn_blocking(move || {
+        hashes
+            .into_iter()
+            .find(|hash| totp::check_recovery_code(&code, hash))
+    })
+    .await
+    .map_err(error::err_into_500)?;
+    let Some(hash) = matched else {
+        return Ok(false);
+    };
+    let used = state
+        .db
+        .use_recovery_code(name.into(), hash)
+        .await
+        .map_err(error::db_error)?;
+    if used {
+        tracing::warn!("Admin {name:?} used a recovery code");
+    }
+    Ok(used)
+}
+
+pub async fn handle_loginpage(
+    CsrfToken(csrf): CsrfToken,
+    session: ReadableSession,
+) -> impl IntoResponse {
+    if pending_login(&session).is_none() {
+        return Err(Redirect::to("/admin/login"));
+    }
+    Ok(templates::LoginTotp {
+        flash: Flash::None,
+        csrf,
+    })
+}
+
+pub async fn handle_login(
+    State(state): State<AppState>,
+    CsrfToken(csrf): CsrfToken,
+    mut session: WritableSession,
+    xforwardedfor: Option<TypedHeader<headers::XForwardedFor>>,
+    Form(form): Form<CodeForm>,
+) -> Result<Response, Response<Body>> {
+    let Some(pending) = pending_login(&session) else {
+        session.remove(PENDING_LOGIN);
+        return Ok(Redirect::to("/admin/login").into_response());
+    };
+    let ip = throttle::client_ip(xforwardedfor.as_deref());
+    let throttle = &state.login_throttle;
+    if let Some(wait) = throttle.retry_after(ip, &pending.name) {
+        tracing::warn!("Throttled admin login for {:?} from {ip}", pending.name);
+        let page = templates::LoginTotp {
+            flash: Flash::Error(throttle::TOO_MANY_ATTEMPTS.into()),
+            csrf,
+        };
+        return Ok(throttle::too_many_attempts(wait, page));
+    }
+
+    let totp = state
+        .db
+        .get_totp(pending.name.clone())
+        .await
+        .map_err(error::db_error)?;
+    // 2FA was reset from the command line in the meantime
+    let Some(totp) = totp.filter(|t| t.enabled) else {
+        session.remove(PENDING_LOGIN);
+        return Ok(Redirect::to("/admin/login").into_response());
+    };
+    if !check_code(&state, &pending.name, &totp, &form.code).await? {
+        tracing::warn!("Failed admin login code for {:?} from {ip}", pending.name);
+        throttle.record_failure(ip, &pending.name);
+        let page = templates::LoginTotp {
+            flash: Flash::Error("Invalid code".into()),
+            csrf,
+        };
+        return Ok(page.into_response());
+    }
+    throttle.record_success(ip, &pending.name);
+    session.remove(PENDING_LOGIN);
+    session.insert_raw("admin", pending.name);
+    Ok(Redirect::to("/admin").into_response())
+}
+
+pub async fn handle_setup(
+    State(state): State<AppState>,
+    CsrfToken(csrf): CsrfToken,
+    mut session: WritableSession,
+) -> Result<Response, Response<Body>> {
+    let name = session.get_raw("admin").unwrap_or_default();
+    let existing = state
+        .db
+        .get_totp(name.clone())
+        .await
+        .map_err(error::db_error)?;
+    if existing.is_some_and(|t| t.enabled) {
+        session
+            .insert(
+                "flash",
+                Flash::Error("Two-factor authentication is already enabled".into()),
+            )
+            .unwrap();
+        return Ok(Redirect::to("/admin").into_response());
+    }
+    let secret = totp::generate_secret();
+    state
+        .db
+        .begin_totp_setup(name.clone(), TotpSecret(secret.clone()))
+        .await
+        .map_err(error::db_error)?;
+    Ok(setup_page(&name, &secret, Flash::None, csrf).into_response())
+}
+
+fn setup_page(name: &str, secret: &[u8], flash: Flash, csrf: String) -> templates::TotpSetup {
+    templates::TotpSetup {
+        flash,
+        uri: totp::otpauth_uri(name, secret),
+        secret: totp::base32(secret),
+        csrf,
+    }
+}
+
+pub async fn handle_enable(
+    State(state): State<AppState>,
+    CsrfToken(csrf): CsrfToken,
+    session: ReadableSession,
+    Form(form): Form<CodeForm>,
+) -> Result<Response, Response<Body>> {
+    let name = session.get_raw("admin").unwrap_or_default();
+    drop(session);
+    let totp = state
+        .db
+        .get_totp(name.clone())
+        .await
+        .map_err(error::db_error)?;
+    let Some(totp) = totp.filter(|t| !t.enabled) else {
+        return Ok(Redirect::to("/admin").into_response());
+    };
+    let Some(step) = totp::verify(&totp.secret.0, &form.code, now()) else {
+        let flash = Flash::Error("Invalid code, check your device's clock".into());
+        return Ok(setup_page(&name, &totp.secret.0, flash, csrf).into_response());
+    };
+
+    let codes = totp::generate_recovery_codes();
+    let hashes = tokio::task::spawn_blocking({
+        let codes = codes.clone();
+        move || codes.iter().map(|c| totp::hash_recovery_code(c)).collect()
+    })
+    .await
+    .map_err(error::err_into_500)?;
+    state
+        .db
+        .enable_totp(name.clone(), step, hashes)
+        .await
+        .map_err(error::db_error)?;
+    tracing::info!("Admin {name:?} enabled two-factor authentication");
+    Ok(templates::TotpRecovery { codes }.into_response())
+}
+
+pub async fn handle_disable(
+    State(state): State<AppState>,
+    mut session: WritableSession,
+    xforwardedfor: Option<TypedHeader<headers::XForwardedFor>>,
+    Form(form): Form<CodeForm>,
+) -> Result<Redirect, Response<Body>> {
+    let name = session.get_raw("admin").unwrap_or_default();
+    let totp = state
+        .db
+        .get_totp(name.clone())
+        .await
+        .map_err(error::db_error)?;
+    let Some(totp) = totp.filter(|t| t.enabled) else {
+        return Ok(Redirect::to("/admin"));
+    };
+    // throttled like logging in, or a stolen session could guess its way out of 2FA
+    let ip = throttle::client_ip(xforwardedfor.as_deref());
+    let throttle = &state.login_throttle;
+    if throttle.retry_after(ip, &name).is_some() {
+        tracing::warn!("Throttled disabling two-factor authentication for {name:?} from {ip}");
+        let flash = Flash::Error(throttle::TOO_MANY_ATTEMPTS.into());
+        session.insert("flash", flash).unwrap();
+        return Ok(Redirect::to("/admin"));
+    }
+    let flash = if check_code(&state, &name, &totp, &form.code).await? {
+        throttle.record_success(ip, &name);
+        state
+            .db
+            .reset_totp(name.clone())
+            .await
+            .map_err(error::db_error)?;
+        tracing::info!("Admin {name:?} disabled two-factor authentication");
+        Flash::Success("Two-factor authentication disabled".into())
+    } else {
+        tracing::warn!("Failed code to disable two-factor authentication for {name:?} from {ip}");
+        throttle.record_failure(ip, &name);
+        Flash::Error("Invalid code".into())
+    };
+    session.insert("flash", flash).unwrap();
+    Ok(Redirect::to("/admin"))
+}
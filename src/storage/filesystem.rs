@@ -0,0 +1,316 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::SystemTime,
+};
+
+use axum::{async_trait, body::Bytes};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt},
+};
+
+use super::{Object, Storage};
+
+/// Images as files in a directory, `image_path` in the configuration. Each one
+/// goes two directories deep, `ab/cd/<name>` after the start of its name's hash,
+/// so no directory grows too large to list. Images from before that are kept
+/// right in the directory until `migrate` moves them, and are found either way.
+pub struct Filesystem {
+    directory: PathBuf,
+}
+
+impl Filesystem {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+
+    fn sharded(&self, name: &str) -> io::Result<PathBuf> {
+        check_name(name)?;
+        Ok(self.directory.join(shard(name)).join(name))
+    }
+
+    fn flat(&self, name: &str) -> io::Result<PathBuf> {
+        check_name(name)?;
+        Ok(self.directory.join(name))
+    }
+
+    /// Where an image is, whether it's been sharded or not.
+    async fn locate(&self, name: &str) -> io::Result<Option<PathBuf>> {
+        for path in [self.sharded(name)?, self.flat(name)?] {
+            match tokio::fs::metadata(&path).await {
+                Ok(metadata) if metadata.is_file() => return Ok(Some(path)),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Moves the images kept right in the directory into their shards, returning
+    /// how many were moved. Safe to run while the server is up.
+    pub fn migrate(&self) -> io::Result<usize> {
+        let mut moved = 0;
+        let mut touched = BTreeSet::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let Ok(name) = entry.file_name().into_string() else {
+                tracing::warn!(
+                    "Skipping image with a non UTF-8 name {:?}",
+                    entry.file_name()
+                );
+                continue;
+            };
+            // staged uploads are moved by whatever is writing them
+            if name.starts_with('.') {
+                continue;
+            }
+            let path = self.sharded(&name)?;
+            if path.exists() {
+                tracing::warn!("Leaving {name} in place, its sharded path is taken");
+                continue;
+            }
+            let shard = path.parent().unwrap();
+            fs::create_dir_all(shard)?;
+            fs::rename(entry.path(), &path)?;
+            touched.insert(shard.to_owned());
+            moved += 1;
+        }
+        for shard in &touched {
+            sync_directory(shard)?;
+            sync_directory(shard.parent().unwrap())?;
+        }
+        sync_directory(&self.directory)?;
+        Ok(moved)
+    }
+}
+
+#[async_trait]
+impl Storage for Filesystem {
+    /// Writes a temporary file and renames it into place once it's synced, then
+    /// syncs the directories so the rename itself is on disk.
+    async fn put(&self, name: &str, data: Bytes) -> io::Result<()> {
+        let path = self.sharded(name)?;
+        let temp = self.flat(&format!(".{name}.tmp"))?;
+        let directory = self.directory.clone();
+        tokio::task::spawn_blocking(move || {
+            let shard = path.parent().unwrap();
+            let created = !shard.is_dir();
+            fs::create_dir_all(shard)?;
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&temp)?;
+            let result = (|| {
+                file.write_all(&data)?;
+                file.sync_all()?;
+                fs::rename(&temp, &path)
+            })();
+            if let Err(e) = result {
+                let _ = fs::remove_file(&temp);
+                return Err(e);
+            }
+            sync_directory(shard)?;
+            if created {
+                sync_directory(shard.parent().unwrap())?;
+            }
+            sync_directory(&directory)
+        })
+        .await?
+    }
+
+    async fn get(&self, name: &str, range: Option<Range<u64>>) -> io::Result<Option<Object>> {
+        let Some(path) = self.locate(name).await? else {
+            return Ok(None);
+        };
+        let mut file = match File::open(path).await {
+            Ok(file) => file,
+            // deleted, or moved into its shard, since it was located
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let metadata = file.metadata().await?;
+        let body: Pin<Box<dyn AsyncRead + Send>> = match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                Box::pin(file.take(range.end.saturating_sub(range.start)))
+            }
+            None => Box::pin(file),
+        };
+        Ok(Some(Object {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            body,
+        }))
+    }
+
+    async fn exists(&self, name: &str) -> io::Result<bool> {
+        Ok(self.locate(name).await?.is_some())
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<()> {
+        for path in [self.sharded(name)?, self.flat(name)?] {
+            match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> io::Result<Vec<(String, SystemTime)>> {
+        let directory = self.directory.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut files = Vec::new();
+            list_files(&directory, 2, &mut files)?;
+            Ok(files)
+        })
+        .await?
+    }
+}
+
+/// The directories an image goes in, `ab/cd` after the start of its name's hash.
+fn shard(name: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+    format!("{}/{}", &hash[..2], &hash[2..4])
+}
+
+/// The image name in a path that's either just the name, or the name under its
+/// shard as it's laid out on disk.
+pub fn unshard(path: &str) -> Option<&str> {
+    match path.rsplit_once('/') {
+        None => Some(path),
+        Some((directories, name)) if directories == shard(name) => Some(name),
+        Some(_) => None,
+    }
+}
+
+/// Image names are file names, which can't lead out of the directory.
+fn check_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid image name {name:?}"),
+        ));
+    }
+    Ok(())
+}
+
+/// Collects the files in `directory` and, `depth` levels down, in its shards.
+fn list_files(
+    directory: &Path,
+    depth: usize,
+    files: &mut Vec<(String, SystemTime)>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => {
+                tracing::warn!("Skipping image with a non UTF-8 name {name:?}");
+                continue;
+            }
+        };
+        if metadata.is_file() {
+            files.push((name, metadata.modified()?));
+        } else if metadata.is_dir() && depth > 0 && is_shard(&name) {
+            list_files(&entry.path(), depth - 1, files)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_shard(name: &str) -> bool {
+    name.len() == 2 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn sync_directory(directory: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    fs::File::open(directory)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = directory;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use rand::{
+        distributions::{Alphanumeric, DistString},
+        thread_rng,
+    };
+
+    use super::*;
+
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new() -> Self {
+            let name = Alphanumeric.sample_string(&mut thread_rng(), 12);
+            let directory = env::temp_dir().join(format!("zhaba-test-{name}"));
+            fs::create_dir_all(&directory).unwrap();
+            Self(directory)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_files() {
+        let scratch = Scratch::new();
+        super::super::exercise(&Filesystem::new(scratch.0.clone())).await;
+    }
+
+    #[tokio::test]
+    async fn finds_and_migrates_flat_images() {
+        let scratch = Scratch::new();
+        let storage = Filesystem::new(scratch.0.clone());
+        storage.put("new.png", Bytes::from("new")).await.unwrap();
+        assert!(storage.sharded("new.png").unwrap().is_file());
+        fs::write(scratch.0.join("old.png"), "old").unwrap();
+        fs::write(scratch.0.join(".staged.png.tmp"), "").unwrap();
+        assert!(storage.exists("old.png").await.unwrap());
+        assert_eq!(storage.get("old.png", None).await.unwrap().unwrap().len, 3);
+        assert!(storage.get("../old.png", None).await.is_err());
+
+        assert_eq!(storage.migrate().unwrap(), 1);
+        assert!(!scratch.0.join("old.png").exists());
+        assert!(storage.sharded("old.png").unwrap().is_file());
+        assert_eq!(storage.migrate().unwrap(), 0);
+        let mut names: Vec<_> = storage
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        names.sort_unstable();
+        assert_eq!(names, [".staged.png.tmp", "new.png", "old.png"]);
+
+        assert_eq!(unshard("old.png"), Some("old.png"));
+        let path = storage.sharded("old.png").unwrap();
+        let path = path.strip_prefix(&scratch.0).unwrap().to_str().unwrap();
+        assert_eq!(unshard(path), Some("old.png"));
+        assert_eq!(unshard("00/00/old.png"), None);
+
+        storage.delete(".staged.png.tmp").await.unwrap();
+        storage.delete("old.png").await.unwrap();
+        assert!(!storage.exists("old.png").await.unwrap());
+        assert_eq!(storage.list().await.unwrap().len(), 1);
+    }
+}
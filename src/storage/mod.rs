@@ -0,0 +1,149 @@
+use std::{
+    fmt,
+    future::Future,
+    io::{self, Read},
+    ops::{Deref, Range},
+    pin::Pin,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use axum::{async_trait, body::Bytes};
+use color_eyre::{eyre::eyre, Result};
+use tokio::{io::AsyncRead, runtime::Handle};
+use tokio_util::io::SyncIoBridge;
+
+use crate::config::{self, Config};
+
+mod filesystem;
+mod s3;
+
+pub use self::{
+    filesystem::{unshard, Filesystem},
+    s3::S3,
+};
+
+/// A stored image, ready to be streamed out.
+pub struct Object {
+    pub len: u64,
+    /// When it was written, if the storage keeps track.
+    pub modified: Option<SystemTime>,
+    pub body: Pin<Box<dyn AsyncRead + Send>>,
+}
+
+/// Where images are kept, by the generated names posts refer to them with.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores an image durably, it's never visible half-written.
+    async fn put(&self, name: &str, data: Bytes) -> io::Result<()>;
+    /// `None` if there's no such image. With a `range`, the body only holds the
+    /// bytes in it that the image has, but `len` is still the whole image's.
+    async fn get(&self, name: &str, range: Option<Range<u64>>) -> io::Result<Option<Object>>;
+    async fn exists(&self, name: &str) -> io::Result<bool>;
+    /// Deleting an image that's already gone isn't an error.
+    async fn delete(&self, name: &str) -> io::Result<()>;
+    /// Every stored image along with when it was written.
+    async fn list(&self) -> io::Result<Vec<(String, SystemTime)>>;
+}
+
+/// The configured storage. Besides being used from async code, it can be used
+/// from blocking tasks, which aren't part of the runtime, with `open_blocking`.
+#[derive(Clone)]
+pub struct Images {
+    storage: Arc<dyn Storage>,
+    runtime: Handle,
+}
+
+impl Images {
+    /// Must be called from within the runtime.
+    pub fn new(cfg: &Config) -> Result<Self> {
+        let storage: Arc<dyn Storage> = match &cfg.storage {
+            config::Storage::Filesystem => {
+                if !cfg.image_path.is_dir() {
+                    return Err(eyre!(
+                        "The image path {:?} is not a directory",
+                        cfg.image_path
+                    ));
+                }
+                Arc::new(Filesystem::new(cfg.image_path.clone()))
+            }
+            config::Storage::S3(options) => Arc::new(S3::new(options)?),
+        };
+        Ok(Self::with_storage(storage))
+    }
+
+    pub fn with_storage(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            storage,
+            runtime: Handle::current(),
+        }
+    }
+
+    /// Waits for a storage operation on a thread outside the runtime. The runtime
+    /// has to be running elsewhere, as it is while anything awaits the caller.
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    /// Opens an image for reading on a thread outside the runtime.
+    pub fn open_blocking(&self, name: &str) -> io::Result<Option<(u64, impl Read)>> {
+        let object = self.block_on(self.storage.get(name, None))?;
+        Ok(object.map(|o| {
+            let reader = SyncIoBridge::new_with_handle(o.body, self.runtime.clone());
+            (o.len, reader)
+        }))
+    }
+}
+
+impl Deref for Images {
+    type Target = dyn Storage;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.storage
+    }
+}
+
+impl fmt::Debug for Images {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Images(...)")
+    }
+}
+
+#[cfg(test)]
+async fn exercise(storage: &dyn Storage) {
+    use tokio::io::AsyncReadExt;
+
+    assert!(storage.get("a.png", None).await.unwrap().is_none());
+    assert!(!storage.exists("a.png").await.unwrap());
+    storage.put("a.png", Bytes::from("first")).await.unwrap();
+    storage.put("b.jpg", Bytes::from("second")).await.unwrap();
+    assert!(storage.exists("a.png").await.unwrap());
+
+    for (range, expected) in [
+        (None, &b"second"[..]),
+        (Some(1..4), b"eco"),
+        (Some(3..16), b"ond"),
+        (Some(6..16), b""),
+    ] {
+        let mut object = storage.get("b.jpg", range).await.unwrap().unwrap();
+        assert_eq!(object.len, 6);
+        let mut data = Vec::new();
+        object.body.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, expected);
+    }
+
+    let mut names: Vec<_> = storage
+        .list()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    names.sort_unstable();
+    assert_eq!(names, ["a.png", "b.jpg"]);
+
+    storage.delete("a.png").await.unwrap();
+    storage.delete("a.png").await.unwrap();
+    assert!(!storage.exists("a.png").await.unwrap());
+    assert_eq!(storage.list().await.unwrap().len(), 1);
+}
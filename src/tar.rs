@@ -0,0 +1,197 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+const BLOCK: usize = 512;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+fn padding(size: u64) -> u64 {
+    (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64
+}
+
+fn octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    field[..digits].copy_from_slice(format!("{value:0digits$o}").as_bytes());
+    field[digits] = 0;
+}
+
+fn checksum(header: &[u8; BLOCK]) -> u64 {
+    // the checksum field itself counts as spaces
+    header[..148]
+        .iter()
+        .chain(&[b' '; 8])
+        .chain(&header[156..])
+        .map(|b| u64::from(*b))
+        .sum()
+}
+
+fn parse_octal(field: &[u8]) -> io::Result<u64> {
+    let digits = std::str::from_utf8(field)
+        .map_err(|_| invalid("invalid number in tar header"))?
+        .trim_matches(|c: char| c == '\0' || c == ' ');
+    u64::from_str_radix(digits, 8).map_err(|_| invalid("invalid number in tar header"))
+}
+
+/// Writes just enough of the ustar format for backups: regular files with
+/// short names, readable by any `tar`.
+pub struct Builder<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Builder<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    /// Appends a file of exactly `size` bytes read from `data`.
+    pub fn append(&mut self, name: &str, size: u64, mtime: u64, data: impl Read) -> io::Result<()> {
+        if name.is_empty() || name.len() > 100 {
+            return Err(invalid(format!("can't store {name:?} in a tar header")));
+        }
+        let mut header = [0u8; BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        octal(&mut header[100..108], 0o644);
+        octal(&mut header[108..116], 0);
+        octal(&mut header[116..124], 0);
+        octal(&mut header[124..136], size);
+        octal(&mut header[136..148], mtime);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        let sum = checksum(&header);
+        header[148..156].copy_from_slice(format!("{sum:06o}\0 ").as_bytes());
+        self.out.write_all(&header)?;
+
+        let copied = io::copy(&mut data.take(size), &mut self.out)?;
+        if copied != size {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("{name} shrank while being archived"),
+            ));
+        }
+        self.out.write_all(&[0; BLOCK][..padding(size) as usize])
+    }
+
+    /// Writes the end-of-archive marker, handing back the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0; BLOCK * 2])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+pub struct Archive<R: Read> {
+    input: R,
+    /// Bytes left of the current entry, padding included.
+    pending: u64,
+}
+
+impl<R: Read> Archive<R> {
+    pub fn new(input: R) -> Self {
+        Self { input, pending: 0 }
+    }
+
+    /// Moves on to the next file, skipping whatever's left of the current one.
+    /// `None` at the end-of-archive marker.
+    pub fn next_entry(&mut self) -> io::Result<Option<Entry<'_, R>>> {
+        io::copy(&mut (&mut self.input).take(self.pending), &mut io::sink())?;
+        self.pending = 0;
+        let mut header = [0u8; BLOCK];
+        self.input
+            .read_exact(&mut header)
+            .map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => invalid("truncated tar archive"),
+                _ => e,
+            })?;
+        if header.iter().all(|b| *b == 0) {
+            return Ok(None);
+        }
+        if &header[257..262] != b"ustar" {
+            return Err(invalid("not a ustar archive"));
+        }
+        if parse_octal(&header[148..156])? != checksum(&header) {
+            return Err(invalid("tar header checksum mismatch"));
+        }
+        if !matches!(header[156], b'0' | 0) {
+            return Err(invalid(
+                "tar archive contains something besides regular files",
+            ));
+        }
+        let name_len = header[..100].iter().position(|b| *b == 0).unwrap_or(100);
+        let name = std::str::from_utf8(&header[..name_len])
+            .map_err(|_| invalid("tar entry name isn't UTF-8"))?
+            .to_string();
+        let size = parse_octal(&header[124..136])?;
+        self.pending = size + padding(size);
+        Ok(Some(Entry {
+            name,
+            left: size,
+            archive: self,
+        }))
+    }
+}
+
+pub struct Entry<'a, R: Read> {
+    pub name: String,
+    left: u64,
+    archive: &'a mut Archive<R>,
+}
+
+impl<R: Read> Read for Entry<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        if max == 0 {
+            return Ok(0);
+        }
+        let read = self.archive.input.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        self.left -= read as u64;
+        self.archive.pending -= read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut builder = Builder::new(Vec::new());
+        builder.append("a.txt", 5, 0, &b"hello"[..]).unwrap();
+        builder.append("images/b.png", 0, 1, &b""[..]).unwrap();
+        let big = vec![7u8; 1300];
+        builder.append("big", 1300, 2, &big[..]).unwrap();
+        let tar = builder.finish().unwrap();
+        assert_eq!(tar.len() % BLOCK, 0);
+
+        let mut archive = Archive::new(&tar[..]);
+        let mut entry = archive.next_entry().unwrap().unwrap();
+        assert_eq!(entry.name, "a.txt");
+        let mut first = [0; 2];
+        entry.read_exact(&mut first).unwrap();
+        assert_eq!(&first, b"he");
+        // the rest of a.txt gets skipped
+        let entry = archive.next_entry().unwrap().unwrap();
+        assert_eq!(entry.name, "images/b.png");
+        let mut entry = archive.next_entry().unwrap().unwrap();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).unwrap();
+        assert_eq!(data, big);
+        assert!(archive.next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_damage() {
+        let mut builder = Builder::new(Vec::new());
+        builder.append("a.txt", 5, 0, &b"hello"[..]).unwrap();
+        assert!(builder.append("short", 10, 0, &b"hello"[..]).is_err());
+        let mut tar = builder.finish().unwrap();
+        tar[0] = b'b';
+        assert!(Archive::new(&tar[..]).next_entry().is_err());
+        assert!(Archive::new(&tar[..BLOCK / 2]).next_entry().is_err());
+    }
+}
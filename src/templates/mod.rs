@@ -1,6 +1,14 @@
+use crate::{
+    challenge::{Challenge, Prompt},
+    filter::Verdict,
+    markup::Example,
+};
 use askama::Template;
 use chrono::{Datelike, Utc};
-use models::{Board, Flash};
+use models::{
+    Ban, Board, BoardSettings, ChallengeMode, Filter, FilterAction, Flash, Identity, IdentityMode,
+    ImagePolicy, IpMask, PatternKind, Post, UntrustedPoster,
+};
 
 pub mod models;
 
@@ -22,12 +30,22 @@ pub struct BoardView {
     pub year: i32,
     pub month: u32,
     pub posts: Vec<models::Post>,
+    pub challenge: Option<Challenge>,
+    pub csrf: String,
 }
 
-#[derive(Template, Default)]
+#[derive(Template)]
 #[template(path = "login.html")]
 pub struct Login {
     pub flash: Flash,
+    pub csrf: String,
+}
+
+#[derive(Template)]
+#[template(path = "login_totp.html")]
+pub struct LoginTotp {
+    pub flash: Flash,
+    pub csrf: String,
 }
 
 #[derive(Template)]
@@ -35,8 +53,62 @@ pub struct Login {
 pub struct AdminHome {
     pub flash: Flash,
     pub boards: Vec<Board>,
+    pub new_board: BoardSettings,
+    pub totp_enabled: bool,
+    pub csrf: String,
+}
+
+#[derive(Template)]
+#[template(path = "delete_board.html")]
+pub struct DeleteBoard {
+    pub board: Board,
+    pub posts: i64,
+    /// How many of the posts have an image.
+    pub images: i64,
+    pub can_archive: bool,
+    pub csrf: String,
+}
+
+#[derive(Template)]
+#[template(path = "queue.html")]
+pub struct ModerationQueue {
+    pub flash: Flash,
+    /// Pending posts, oldest first, with the name of their board.
+    pub posts: Vec<(String, Post)>,
+    pub untrusted: Vec<UntrustedPoster>,
+    pub csrf: String,
+}
+
+#[derive(Template)]
+#[template(path = "filters.html")]
+pub struct FilterAdmin {
+    pub flash: Flash,
+    pub filters: Vec<Filter>,
+    pub bans: Vec<Ban>,
+    /// Text from the preview form and what the filters made of it.
+    pub test: String,
+    pub verdict: Option<Verdict>,
+    pub csrf: String,
+}
+
+#[derive(Template)]
+#[template(path = "totp_setup.html")]
+pub struct TotpSetup {
+    pub flash: Flash,
+    pub uri: String,
+    pub secret: String,
+    pub csrf: String,
+}
+
+#[derive(Template)]
+#[template(path = "totp_recovery.html")]
+pub struct TotpRecovery {
+    pub codes: Vec<String>,
 }
 
 #[derive(Template)]
 #[template(path = "about.html")]
-pub struct About;
+pub struct About {
+    pub examples: Vec<Example>,
+    pub boards: Vec<Board>,
+}
@@ -3,18 +3,36 @@ use std::borrow::Cow;
 
 use serde::{Deserialize, Serialize};
 
-use crate::whois::WhoisResult;
+use crate::{markup::TagSet, moderation::Untrusted, whois::WhoisResult};
 
 #[derive(Debug)]
 pub struct Post {
     pub id: u64,
+    /// Rendered HTML, filled in from `source` when the post is displayed.
     pub content: String,
+    /// Raw markup as typed by the poster, `None` for posts stored as HTML.
+    pub source: Option<String>,
     pub image: Option<String>,
     pub ip: String,
     pub whois: Option<WhoisResult>,
     pub reply: Option<ReplyTo>,
     pub time: NaiveDateTime,
     pub board: u64,
+    pub thread: Option<u64>,
+    pub name: Option<String>,
+    pub tripcode: Option<String>,
+    pub identity: Identity,
+    /// Held for review, only visible to admins until approved.
+    pub pending: bool,
+}
+
+/// What the post header shows about the poster.
+#[derive(Debug, Default)]
+pub enum Identity {
+    #[default]
+    Full,
+    Maintainer,
+    Pseudonym(String),
 }
 
 #[derive(Debug)]
@@ -25,12 +43,242 @@ pub struct ReplyTo {
     pub board_name: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Board {
     pub id: i64,
     pub name: String,
     pub description: String,
     pub color: u32,
+    pub settings: BoardSettings,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct BoardSettings {
+    pub identity: IdentityMode,
+    pub ip_mask: Option<IpMask>,
+    pub locked: bool,
+    pub images: ImagePolicy,
+    pub max_post_length: Option<usize>,
+    pub hidden: bool,
+    pub tags: Option<TagSet>,
+    pub challenge: ChallengeMode,
+    /// Every post waits for approval.
+    pub moderated: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImagePolicy {
+    #[default]
+    Allowed,
+    Required,
+    Forbidden,
+}
+
+impl ImagePolicy {
+    pub const ALL: [Self; 3] = [Self::Allowed, Self::Required, Self::Forbidden];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Allowed => "allowed",
+            Self::Required => "required",
+            Self::Forbidden => "forbidden",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Allowed => "images allowed",
+            Self::Required => "images required",
+            Self::Forbidden => "images forbidden",
+        }
+    }
+}
+
+/// When posters have to solve an anti-spam challenge.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeMode {
+    Off,
+    /// Only for addresses the post rate tracker finds suspicious.
+    #[default]
+    Auto,
+    Always,
+}
+
+impl ChallengeMode {
+    pub const ALL: [Self; 3] = [Self::Off, Self::Auto, Self::Always];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Auto => "auto",
+            Self::Always => "always",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Off => "no anti-spam challenge",
+            Self::Auto => "challenge suspicious posters",
+            Self::Always => "always challenge posters",
+        }
+    }
+}
+
+/// A word filter checked against every new post.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub id: i64,
+    pub pattern: String,
+    pub kind: PatternKind,
+    pub action: FilterAction,
+    /// What matches are replaced with, only used by `FilterAction::Replace`.
+    pub replacement: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PatternKind {
+    /// Matched case-insensitively anywhere in the post.
+    #[default]
+    Literal,
+    Regex,
+}
+
+impl PatternKind {
+    pub const ALL: [Self; 2] = [Self::Literal, Self::Regex];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Literal => "literal",
+            Self::Regex => "regex",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Literal => "literal text",
+            Self::Regex => "regular expression",
+        }
+    }
+}
+
+/// What happens to a post matching a filter, in increasing order of severity.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    #[default]
+    Replace,
+    Hold,
+    Reject,
+    Ban,
+}
+
+impl FilterAction {
+    pub const ALL: [Self; 4] = [Self::Replace, Self::Hold, Self::Reject, Self::Ban];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Replace => "replace",
+            Self::Hold => "hold",
+            Self::Reject => "reject",
+            Self::Ban => "ban",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Replace => "replace the match",
+            Self::Hold => "hold for review",
+            Self::Reject => "reject the post",
+            Self::Ban => "reject and ban the poster",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UntrustedPoster {
+    pub id: i64,
+    pub entry: Untrusted,
+    pub note: String,
+    pub time: NaiveDateTime,
+}
+
+/// An address that isn't allowed to post until the ban expires, if ever.
+#[derive(Debug)]
+pub struct Ban {
+    pub id: i64,
+    pub ip: String,
+    pub reason: String,
+    pub time: NaiveDateTime,
+    pub expires: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityMode {
+    #[default]
+    Full,
+    Maintainer,
+    Daily,
+    Thread,
+}
+
+impl IdentityMode {
+    pub const ALL: [Self; 4] = [Self::Full, Self::Maintainer, Self::Daily, Self::Thread];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Maintainer => "maintainer",
+            Self::Daily => "daily",
+            Self::Thread => "thread",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Full => "IP, MNT and ASN",
+            Self::Maintainer => "MNT only",
+            Self::Daily => "ID per day",
+            Self::Thread => "ID per thread",
+        }
+    }
+}
+
+/// How much of a poster's IP address anonymous viewers get to see.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpMask {
+    #[default]
+    Show,
+    Truncate,
+    Hide,
+}
+
+impl IpMask {
+    pub const ALL: [Self; 3] = [Self::Show, Self::Truncate, Self::Hide];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Show => "show",
+            Self::Truncate => "truncate",
+            Self::Hide => "hide",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.name() == name)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Show => "full IP",
+            Self::Truncate => "IP truncated to /24 or /48",
+            Self::Hide => "IP hidden",
+        }
+    }
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -0,0 +1,178 @@
+use hmac::{Hmac, Mac};
+use pbkdf2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, Salt, SaltString},
+    Params, Pbkdf2,
+};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng, RngCore,
+};
+use sha1::Sha1;
+
+const ISSUER: &str = "zhaba";
+const SECRET_LENGTH: usize = 20;
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+// accepted clock drift, in steps either way
+const WINDOW: u64 = 1;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+const RECOVERY_CODE_ROUNDS: u32 = 10_000;
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_LENGTH];
+    thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// RFC 4648 base32 without padding, the format authenticator apps expect.
+pub fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    for chunk in bytes.chunks(5) {
+        let mut buf = [0u8; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let bits = buf.iter().fold(0u64, |acc, b| acc << 8 | u64::from(*b));
+        for i in 0..(chunk.len() * 8).div_ceil(5) {
+            encoded.push(BASE32[(bits >> (35 - i * 5)) as usize & 31].into());
+        }
+    }
+    encoded
+}
+
+/// The `otpauth://` URI for enrolling `account` in an authenticator app.
+pub fn otpauth_uri(account: &str, secret: &[u8]) -> String {
+    let account: String = account
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => char::from(b).into(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect();
+    format!(
+        "otpauth://totp/{ISSUER}:{account}?secret={}&issuer={ISSUER}&digits={DIGITS}&period={STEP_SECS}",
+        base32(secret)
+    )
+}
+
+/// Checks a code against the steps around `unix_time`, returning the matching
+/// step so the caller can refuse to accept it twice.
+pub fn verify(secret: &[u8], code: &str, unix_time: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = unix_time / STEP_SECS;
+    (current.saturating_sub(WINDOW)..=current + WINDOW).find(|step| hotp(secret, *step) == code)
+}
+
+/// Single-use codes for when the authenticator is lost, shown once at enrolment.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = Alphanumeric
+                .sample_string(&mut thread_rng(), RECOVERY_CODE_LENGTH)
+                .to_ascii_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Recovery codes are only stored hashed, salted with PBKDF2 and ignoring
+/// case, spaces and dashes. Being random, they need far fewer rounds than a
+/// password would to hold up.
+pub fn hash_recovery_code(code: &str) -> String {
+    let mut salt = [0; Salt::RECOMMENDED_LENGTH];
+    thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).expect("the salt is a valid length");
+    let params = Params {
+        rounds: RECOVERY_CODE_ROUNDS,
+        ..Params::default()
+    };
+    let code = normalize_recovery_code(code);
+    Pbkdf2
+        .hash_password_customized(code.as_bytes(), None, None, params, &salt)
+        .expect("PBKDF2 accepts these parameters")
+        .to_string()
+}
+
+/// Whether `code` is the recovery code hashed as `stored`.
+pub fn check_recovery_code(code: &str, stored: &str) -> bool {
+    let code = normalize_recovery_code(code);
+    if code.len() != RECOVERY_CODE_LENGTH {
+        return false;
+    }
+    PasswordHash::new(stored)
+        .is_ok_and(|hash| Pbkdf2.verify_password(code.as_bytes(), &hash).is_ok())
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let truncated = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    truncated % 10u32.pow(DIGITS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, truncated to 6 digits
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc6238_vectors() {
+        assert_eq!(hotp(RFC_SECRET, 59 / STEP_SECS), 287082);
+        assert_eq!(hotp(RFC_SECRET, 1111111109 / STEP_SECS), 81804);
+        assert_eq!(hotp(RFC_SECRET, 1234567890 / STEP_SECS), 5924);
+        assert_eq!(hotp(RFC_SECRET, 20000000000 / STEP_SECS), 353130);
+    }
+
+    #[test]
+    fn verify_window_and_format() {
+        assert_eq!(verify(RFC_SECRET, "081804", 1111111109), Some(37037036));
+        assert_eq!(
+            verify(RFC_SECRET, " 081804 ", 1111111109 + 30),
+            Some(37037036)
+        );
+        assert_eq!(verify(RFC_SECRET, "081804", 1111111109 + 90), None);
+        assert_eq!(verify(RFC_SECRET, "81804", 1111111109), None);
+        assert_eq!(verify(RFC_SECRET, "+81804", 1111111109), None);
+    }
+
+    #[test]
+    fn base32_encoding() {
+        assert_eq!(base32(b""), "");
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32(RFC_SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn recovery_codes_normalize() {
+        let code = &generate_recovery_codes()[0];
+        let hash = hash_recovery_code(code);
+        assert!(check_recovery_code(
+            &code.to_uppercase().replace('-', " "),
+            &hash
+        ));
+        assert!(!check_recovery_code(&generate_recovery_codes()[0], &hash));
+        assert!(!check_recovery_code("", &hash));
+        // salted, so the same code hashes differently each time
+        assert_ne!(hash, hash_recovery_code(code));
+        assert_eq!(
+            otpauth_uri("a b", RFC_SECRET),
+            "otpauth://totp/zhaba:a%20b?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=zhaba&digits=6&period=30"
+        );
+    }
+}
@@ -1,61 +1,287 @@
+use std::time::Duration;
+
 use color_eyre::{eyre::eyre, Result};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
+    time::timeout,
 };
 
-#[derive(Debug)]
+// a lookup holds up the post, so a slow or chatty server is cut off
+const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct WhoisResult {
     pub asn: u32,
-    pub mnt: String,
+    pub prefix: Option<String>,
+    pub mnt: Vec<String>,
+    pub descr: Option<String>,
 }
 
 pub async fn whois(server: &str, query: &str) -> Result<Option<WhoisResult>> {
-    if server.chars().next() == Some('!') {
+    if server.starts_with('!') {
         return Ok(Some(WhoisResult {
             asn: 4242426969,
-            mnt: "MIETEK-MNT".into(),
+            prefix: Some("172.20.69.0/24".into()),
+            mnt: vec!["MIETEK-MNT".into()],
+            descr: None,
         }));
     }
-    let mut stream = TcpStream::connect(server).await?;
-    let (read, mut write) = stream.split();
-    let mut lines = BufReader::new(read).lines();
+    let lookup = async {
+        let mut stream = TcpStream::connect(server).await?;
+        stream.write_all(query.as_bytes()).await?;
+        stream.write_all(b"\n").await?;
 
-    write.write_all(query.as_bytes()).await?;
-    write.write_all(&[b'\n']).await?;
+        let mut response = Vec::new();
+        stream
+            .take(MAX_RESPONSE_BYTES)
+            .read_to_end(&mut response)
+            .await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = timeout(TIMEOUT, lookup)
+        .await
+        .map_err(|_| eyre!("Whois server {server} didn't answer in time"))??;
+    Ok(parse_response(&String::from_utf8_lossy(&response)))
+}
 
-    loop {
-        if let Some(line) = lines.next_line().await? {
-            if line.starts_with("% Information related to 'route") {
-                break;
+/// Picks the most specific `route`/`route6` object out of an RPSL whois response.
+/// Objects that don't parse are skipped.
+pub fn parse_response(response: &str) -> Option<WhoisResult> {
+    let mut best: Option<(u8, WhoisResult)> = None;
+    for object in split_objects(response) {
+        let result = match parse_route_object(&object) {
+            Ok(Some(result)) => result,
+            Ok(None) => continue,
+            Err(e) => {
+                tracing::debug!("Skipping whois object: {e}");
+                continue;
             }
-        } else {
-            return Ok(None);
+        };
+        let length = result
+            .prefix
+            .as_deref()
+            .and_then(|p| p.rsplit_once('/'))
+            .and_then(|(_, len)| len.parse().ok())
+            .unwrap_or(0);
+        if best.as_ref().is_none_or(|(l, _)| length > *l) {
+            best = Some((length, result));
         }
     }
+    best.map(|(_, result)| result)
+}
+
+fn parse_route_object(object: &[(&str, String)]) -> Result<Option<WhoisResult>> {
+    let Some((class, prefix)) = object.first() else {
+        return Ok(None);
+    };
+    if *class != "route" && *class != "route6" {
+        return Ok(None);
+    }
 
     let mut asn = None;
-    let mut mnt = None;
-
-    while let Some(line) = lines.next_line().await? {
-        if let Some((key, value)) = line.split_once(':') {
-            match key {
-                "origin" => {
-                    let asn_str = value
-                        .trim()
-                        .get(2..)
-                        .ok_or_else(|| eyre!("Invalid ASN format from whois: {value}"))?;
-                    asn = Some(asn_str.parse()?);
+    let mut mnt = Vec::new();
+    let mut descr: Option<String> = None;
+    for (key, value) in object {
+        match *key {
+            "origin" if asn.is_none() => {
+                let asn_str = value
+                    .strip_prefix("AS")
+                    .or_else(|| value.strip_prefix("as"))
+                    .ok_or_else(|| eyre!("Invalid ASN format from whois: {value}"))?;
+                asn = Some(asn_str.parse()?);
+            }
+            "mnt-by" => mnt.push(value.clone()),
+            "descr" => match &mut descr {
+                // joined like continuation lines
+                Some(descr) => {
+                    descr.push(' ');
+                    descr.push_str(value);
+                }
+                None => descr = Some(value.clone()),
+            },
+            _ => {}
+        }
+    }
+
+    Ok(asn.map(|asn| WhoisResult {
+        asn,
+        prefix: Some(prefix.clone()),
+        mnt,
+        descr,
+    }))
+}
+
+/// Splits a response into objects, each a list of attributes. Objects are separated
+/// by blank lines or `%` comments, and continuation lines are folded into the previous value.
+fn split_objects(response: &str) -> Vec<Vec<(&str, String)>> {
+    let mut objects = Vec::new();
+    let mut current: Vec<(&str, String)> = Vec::new();
+    for line in response.lines() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('%') || line.starts_with('#') {
+            if !current.is_empty() {
+                objects.push(std::mem::take(&mut current));
+            }
+        } else if line.starts_with([' ', '\t', '+']) {
+            if let Some((_, value)) = current.last_mut() {
+                let continued = line.trim_start_matches('+').trim();
+                if !continued.is_empty() {
+                    value.push(' ');
+                    value.push_str(continued);
                 }
-                "mnt-by" => mnt = Some(value.trim().to_string()),
-                _ => {}
             }
+        } else if let Some((key, value)) = line.split_once(':') {
+            current.push((key, value.trim().to_string()));
         }
     }
+    if !current.is_empty() {
+        objects.push(current);
+    }
+    objects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DN42_V4: &str = "\
+% This is the dn42 whois query service.
+
+% Information related to 'inetnum/172.20.0.0_24'
+
+inetnum:            172.20.0.0 - 172.20.0.255
+netname:            ANYCAST-SERVICES
+admin-c:            DN42-DN42
+tech-c:             DN42-DN42
+mnt-by:             DN42-MNT
+status:             ASSIGNED
+cidr:               172.20.0.0/24
+source:             DN42
+
+% Information related to 'route/172.20.0.0_16'
+
+route:              172.20.0.0/16
+origin:             AS4242420000
+mnt-by:             DN42-MNT
+source:             DN42
+
+% Information related to 'route/172.20.0.0_24'
+
+route:              172.20.0.0/24
+origin:             AS4242420053
+descr:              dn42 anycast services
+mnt-by:             DN42-MNT
+mnt-by:             LEMON-MNT
+source:             DN42
+
+";
+
+    const DN42_V6: &str = "\
+% This is the dn42 whois query service.
+
+% Information related to 'inet6num/fd42:d42:d42::_48'
+
+inet6num:           fd42:0d42:0d42:0000:0000:0000:0000:0000 - fd42:0d42:0d42:ffff:ffff:ffff:ffff:ffff
+netname:            DN42-ANYCAST
+mnt-by:             DN42-MNT
+source:             DN42
+
+% Information related to 'route6/fd42:d42:d42::_48'
+
+route6:             fd42:d42:d42::/48
+origin:             AS4242420000
+mnt-by:             DN42-MNT
+source:             DN42
+
+";
+
+    const RIPE_V6: &str = "\
+% This is the RIPE Database query service.
+% The objects are in RPSL format.
+
+% Information related to '2001:67c:2e8::/48AS3333'
+
+route6:         2001:67c:2e8::/48
+descr:          RIPE-NCC
+                Amsterdam
+origin:         AS3333
+mnt-by:         RIPE-NCC-MNT
+mnt-by:         RIPE-NCC-RIS-MNT
+created:        2011-03-24T10:17:44Z
+source:         RIPE
+
+% This query was served by the RIPE Database Query Service version 1.109 (ABERDEEN)
+";
+
+    #[test]
+    fn dn42_v4_picks_most_specific_route() {
+        assert_eq!(
+            parse_response(DN42_V4),
+            Some(WhoisResult {
+                asn: 4242420053,
+                prefix: Some("172.20.0.0/24".into()),
+                mnt: vec!["DN42-MNT".into(), "LEMON-MNT".into()],
+                descr: Some("dn42 anycast services".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn dn42_v6_route6() {
+        assert_eq!(
+            parse_response(DN42_V6),
+            Some(WhoisResult {
+                asn: 4242420000,
+                prefix: Some("fd42:d42:d42::/48".into()),
+                mnt: vec!["DN42-MNT".into()],
+                descr: None,
+            })
+        );
+    }
+
+    #[test]
+    fn ripe_v6_with_other_header() {
+        assert_eq!(
+            parse_response(RIPE_V6),
+            Some(WhoisResult {
+                asn: 3333,
+                prefix: Some("2001:67c:2e8::/48".into()),
+                mnt: vec!["RIPE-NCC-MNT".into(), "RIPE-NCC-RIS-MNT".into()],
+                descr: Some("RIPE-NCC Amsterdam".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn no_route_object() {
+        let response =
+            "% Information related to 'inetnum/10.0.0.0_8'\n\ninetnum: 10.0.0.0 - 10.255.255.255\n";
+        assert_eq!(parse_response(response), None);
+    }
+
+    #[test]
+    fn invalid_objects_are_skipped() {
+        assert_eq!(parse_response("route: 10.0.0.0/8\norigin: 1234\n"), None);
+        let response = "\
+route: 10.0.0.0/8
+origin: AS64512
+descr: first
+descr: second
+  continued
 
-    if let (Some(asn), Some(mnt)) = (asn, mnt) {
-        Ok(Some(WhoisResult { asn, mnt }))
-    } else {
-        Ok(None)
+route: 10.1.0.0/16
+origin: 1234
+";
+        assert_eq!(
+            parse_response(response),
+            Some(WhoisResult {
+                asn: 64512,
+                prefix: Some("10.0.0.0/8".into()),
+                mnt: vec![],
+                descr: Some("first second continued".into()),
+            })
+        );
     }
 }